@@ -0,0 +1,212 @@
+use std::ops::Range;
+
+use byteorder::{ByteOrder, LittleEndian};
+use goblin::pe::PE;
+
+/// Size of the PE signature and the COFF file header which precede the optional header
+const PE_SIGNATURE_AND_COFF_HEADER_SIZE: usize = 4 + 20;
+const SECTION_HEADER_SIZE: usize = 40;
+const VIRTUAL_SIZE_OFFSET_IN_SECTION_HEADER: usize = 8;
+
+/// The raw data of an executable section
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub range_in_file: Range<usize>,
+    pub vaddr: usize,
+    pub code: Vec<u8>,
+    /// Size of the section when loaded. Bytes of `code` after it are file padding which isn't
+    /// mapped unless the virtual size is increased.
+    pub virtual_size: usize,
+    /// Largest virtual size which doesn't require moving the following sections
+    pub max_virtual_size: usize,
+    /// File offset of the `VirtualSize` field in the section header
+    pub(crate) virtual_size_offset: usize,
+}
+
+impl Span {
+    /// Writes the (modified) code and virtual size back into `binary`
+    pub fn write_to(&self, binary: &mut [u8]) {
+        binary[self.range_in_file.clone()].copy_from_slice(&self.code);
+        LittleEndian::write_u32(
+            &mut binary[self.virtual_size_offset..],
+            self.virtual_size as u32,
+        );
+    }
+}
+
+#[cfg(test)]
+impl Span {
+    /// Span of `code` at 0x1000 which is mapped completely
+    pub(crate) fn test(code: Vec<u8>) -> Span {
+        Span {
+            range_in_file: 0..code.len(),
+            vaddr: 0x1000,
+            virtual_size: code.len(),
+            max_virtual_size: code.len(),
+            code,
+            virtual_size_offset: 0,
+        }
+    }
+}
+
+/// Returns the index of the span which maps `address` and the offset of `address` in its code
+pub fn locate(spans: &[Span], address: u64) -> Option<(usize, usize)> {
+    spans.iter().enumerate().find_map(|(i, span)| {
+        let offset = address.checked_sub(span.vaddr as u64)? as usize;
+        if offset < span.virtual_size.min(span.code.len()) {
+            Some((i, offset))
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the mapped code starting at `address`
+pub fn code_at(spans: &[Span], address: u64) -> Option<&[u8]> {
+    let (i, offset) = locate(spans, address)?;
+    let span = &spans[i];
+    Some(&span.code[offset..span.virtual_size.min(span.code.len())])
+}
+
+pub fn get_code_segments(pe: &PE<'_>, buffer: &[u8]) -> Vec<Span> {
+    use goblin::pe::section_table::IMAGE_SCN_CNT_CODE;
+
+    let section_headers_offset = pe.header.dos_header.pe_pointer as usize
+        + PE_SIGNATURE_AND_COFF_HEADER_SIZE
+        + pe.header.coff_header.size_of_optional_header as usize;
+    let section_alignment = pe
+        .header
+        .optional_header
+        .as_ref()
+        .map(|optional_header| optional_header.windows_fields.section_alignment as usize)
+        .unwrap_or(0x1000);
+
+    let mut vec = Vec::new();
+    for (i, section) in pe.sections.iter().enumerate() {
+        if section.characteristics & IMAGE_SCN_CNT_CODE > 0 {
+            let range_in_file = section.pointer_to_raw_data as usize
+                ..(section.pointer_to_raw_data + section.size_of_raw_data) as usize;
+            let code = &buffer[range_in_file.clone()];
+            // A virtual size of 0 means that the raw size is used
+            let virtual_size = match section.virtual_size as usize {
+                0 => code.len(),
+                virtual_size => virtual_size,
+            };
+            let mapped_size =
+                (virtual_size + section_alignment - 1) / section_alignment * section_alignment;
+            vec.push(Span {
+                range_in_file,
+                code: code.to_vec(),
+                vaddr: section.virtual_address as usize + pe.image_base,
+                virtual_size,
+                max_virtual_size: mapped_size.min(code.len()).max(virtual_size),
+                virtual_size_offset: section_headers_offset
+                    + i * SECTION_HEADER_SIZE
+                    + VIRTUAL_SIZE_OFFSET_IN_SECTION_HEADER,
+            })
+        }
+    }
+    vec
+}
+
+/// Size of a `RUNTIME_FUNCTION` entry in the exception table
+const RUNTIME_FUNCTION_SIZE: usize = 12;
+
+/// Returns the (absolute) addresses of the entry point, the exported functions and the functions
+/// listed in the exception table
+pub fn function_starts(pe: &PE<'_>, buffer: &[u8]) -> Vec<u64> {
+    let image_base = pe.image_base as u64;
+    let mut starts = vec![image_base + pe.entry as u64];
+    starts.extend(
+        pe.exports
+            .iter()
+            .filter(|export| export.reexport.is_none())
+            .map(|export| image_base + export.rva as u64),
+    );
+
+    let exception_table = pe
+        .header
+        .optional_header
+        .as_ref()
+        .and_then(|optional_header| *optional_header.data_directories.get_exception_table());
+    if let Some(exception_table) = exception_table {
+        if let Some(offset) = rva_to_offset(pe, exception_table.virtual_address as usize) {
+            let end = (offset + exception_table.size as usize).min(buffer.len());
+            for runtime_function in buffer[offset..end].chunks(RUNTIME_FUNCTION_SIZE) {
+                if runtime_function.len() == RUNTIME_FUNCTION_SIZE {
+                    let begin = LittleEndian::read_u32(runtime_function);
+                    starts.push(image_base + u64::from(begin));
+                }
+            }
+        }
+    }
+
+    starts.sort();
+    starts.dedup();
+    starts
+}
+
+/// Size of the header of a block in the base relocation table
+const BASE_RELOCATION_BLOCK_HEADER_SIZE: usize = 8;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+/// Returns the (absolute) addresses which are stored in 64-bit pointers listed in the base
+/// relocation table. These include jump tables and function pointers.
+pub fn relocation_targets(pe: &PE<'_>, buffer: &[u8]) -> Vec<u64> {
+    let relocation_table = pe
+        .header
+        .optional_header
+        .as_ref()
+        .and_then(|optional_header| *optional_header.data_directories.get_base_relocation_table());
+    let relocation_table = match relocation_table {
+        Some(relocation_table) => relocation_table,
+        None => return Vec::new(),
+    };
+    let offset = match rva_to_offset(pe, relocation_table.virtual_address as usize) {
+        Some(offset) => offset,
+        None => return Vec::new(),
+    };
+    let end = (offset + relocation_table.size as usize).min(buffer.len());
+
+    let mut targets = Vec::new();
+    let mut block = offset;
+    while block + BASE_RELOCATION_BLOCK_HEADER_SIZE <= end {
+        let page_rva = LittleEndian::read_u32(&buffer[block..]) as usize;
+        let block_size = LittleEndian::read_u32(&buffer[block + 4..]) as usize;
+        if block_size < BASE_RELOCATION_BLOCK_HEADER_SIZE {
+            break;
+        }
+        let entries =
+            &buffer[block + BASE_RELOCATION_BLOCK_HEADER_SIZE..(block + block_size).min(end)];
+        for entry in entries.chunks_exact(2) {
+            let entry = LittleEndian::read_u16(entry);
+            if entry >> 12 != IMAGE_REL_BASED_DIR64 {
+                continue;
+            }
+            let rva = page_rva + (entry & 0xFFF) as usize;
+            if let Some(pointer) = rva_to_offset(pe, rva) {
+                if pointer + 8 <= buffer.len() {
+                    targets.push(LittleEndian::read_u64(&buffer[pointer..]));
+                }
+            }
+        }
+        block += block_size;
+    }
+
+    targets.sort();
+    targets.dedup();
+    targets
+}
+
+/// Converts a relative virtual address to an offset in the file
+pub fn rva_to_offset(pe: &PE<'_>, rva: usize) -> Option<usize> {
+    pe.sections.iter().find_map(|section| {
+        let start = section.virtual_address as usize;
+        let size = (section.virtual_size as usize).max(section.size_of_raw_data as usize);
+        if rva >= start && rva < start + size && rva - start < section.size_of_raw_data as usize {
+            Some(section.pointer_to_raw_data as usize + rva - start)
+        } else {
+            None
+        }
+    })
+}
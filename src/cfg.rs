@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use crate::binary::{code_at, Span};
+use crate::disassembly::{DecodedInstruction, Disassembler};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    start: u64,
+    end: u64,
+    /// Start addresses of the blocks which may be executed next (not including call targets)
+    successors: Vec<u64>,
+}
+
+impl BasicBlock {
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn range(&self) -> Range<u64> {
+        self.start..self.end
+    }
+
+    pub fn successors(&self) -> &[u64] {
+        &self.successors
+    }
+}
+
+/// Control-flow graph of the code spans recovered by recursive disassembly
+#[derive(Debug, Clone, Default)]
+pub struct ControlFlowGraph {
+    blocks: BTreeMap<u64, BasicBlock>,
+    leaders: BTreeSet<u64>,
+    instructions: BTreeMap<u64, DecodedInstruction>,
+}
+
+impl ControlFlowGraph {
+    /// Disassembles the spans by following the control flow from `entry_points`. Addresses outside
+    /// of the spans aren't followed. Calls end a basic block as the return address may be branched
+    /// to.
+    pub fn recover(
+        spans: &[Span],
+        entry_points: &[u64],
+        disassembler: &Disassembler,
+    ) -> ControlFlowGraph {
+        let mut instructions = BTreeMap::new();
+        let mut leaders = BTreeSet::new();
+        let mut worklist = Vec::new();
+        for &entry_point in entry_points {
+            if code_at(spans, entry_point).is_some() {
+                leaders.insert(entry_point);
+                worklist.push(entry_point);
+            }
+        }
+
+        while let Some(mut address) = worklist.pop() {
+            while !instructions.contains_key(&address) {
+                let code = match code_at(spans, address) {
+                    Some(code) => code,
+                    None => break,
+                };
+                let instruction = match disassembler.decode(code, address) {
+                    Some(instruction) => instruction,
+                    None => break,
+                };
+                let next = instruction.end();
+                let ends_block = instruction.is_control_transfer();
+                if let Some(target) = instruction.branch_target() {
+                    if code_at(spans, target).is_some() {
+                        leaders.insert(target);
+                        worklist.push(target);
+                    }
+                }
+                let falls_through = instruction.falls_through();
+                instructions.insert(address, instruction);
+                if ends_block {
+                    if falls_through {
+                        leaders.insert(next);
+                        worklist.push(next);
+                    }
+                    break;
+                }
+                address = next;
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        let mut block_start = None;
+        for (address, instruction) in &instructions {
+            let start = *block_start.get_or_insert(*address);
+            let next = instruction.end();
+            let continues = !instruction.is_control_transfer()
+                && instructions.contains_key(&next)
+                && !leaders.contains(&next);
+            if continues {
+                continue;
+            }
+
+            let mut successors = Vec::new();
+            if instruction.mnemonic() != "call" {
+                if let Some(target) = instruction.branch_target() {
+                    if instructions.contains_key(&target) {
+                        successors.push(target);
+                    }
+                }
+            }
+            if instruction.falls_through() && instructions.contains_key(&next) {
+                successors.push(next);
+            }
+            blocks.insert(
+                start,
+                BasicBlock {
+                    start,
+                    end: next,
+                    successors,
+                },
+            );
+            block_start = None;
+        }
+
+        ControlFlowGraph {
+            blocks,
+            leaders,
+            instructions,
+        }
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks.values()
+    }
+
+    /// Returns the block which contains `address`
+    pub fn block_containing(&self, address: u64) -> Option<&BasicBlock> {
+        self.blocks
+            .range(..=address)
+            .next_back()
+            .map(|(_, block)| block)
+            .filter(|block| address < block.end)
+    }
+
+    /// Start addresses of all basic blocks
+    pub fn leaders(&self) -> &BTreeSet<u64> {
+        &self.leaders
+    }
+
+    /// All reachable instructions by address
+    pub fn instructions(&self) -> &BTreeMap<u64, DecodedInstruction> {
+        &self.instructions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recover_blocks() {
+        let code = vec![
+            0x74, 0x03, // 0x1000: je 0x1005
+            0x53, // 0x1002: push rbx
+            0xEB, 0x01, // 0x1003: jmp 0x1006
+            0x5B, // 0x1005: pop rbx
+            0xC3, // 0x1006: ret
+            0xCC, // 0x1007: int3 (unreachable)
+        ];
+        let span = Span::test(code);
+        let cfg = ControlFlowGraph::recover(&[span], &[0x1000], &Disassembler::new());
+        let blocks: Vec<_> = cfg
+            .blocks()
+            .map(|block| (block.range(), block.successors().to_vec()))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                (0x1000..0x1002, vec![0x1005, 0x1002]),
+                (0x1002..0x1005, vec![0x1006]),
+                (0x1005..0x1006, vec![0x1006]),
+                (0x1006..0x1007, vec![]),
+            ]
+        );
+        assert_eq!(cfg.block_containing(0x1003).unwrap().start(), 0x1002);
+        assert!(cfg.block_containing(0x1007).is_none());
+    }
+}
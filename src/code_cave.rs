@@ -0,0 +1,186 @@
+use std::ops::Range;
+
+use failure::Fail;
+
+use crate::binary::Span;
+use crate::nasm_assemble;
+use crate::pattern::FillerSet;
+
+/// Minimum length of a run of `int3` padding to be used as code cave
+const MIN_INT3_RUN: usize = 16;
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum CodeCaveError {
+    #[fail(display = "no code cave with at least {} free bytes left", _0)]
+    NoSpace(usize),
+    #[fail(display = "the replaced code is too short for a jump to a code cave")]
+    SiteTooSmall,
+    #[fail(display = "assembly of the relocated replacement failed")]
+    AssemblyFailed,
+}
+
+/// Unused space in a span
+#[derive(Debug, Clone)]
+struct CodeCave {
+    span: usize,
+    /// Free range in the span's code
+    free: Range<usize>,
+}
+
+/// Free space in the code spans which can hold replacements that don't fit at the location of the
+/// match. Caves are runs of `int3` padding between functions and the unused file padding at the
+/// end of code sections (which gets mapped by increasing the section's virtual size).
+#[derive(Debug, Clone)]
+pub struct CodeCaves {
+    caves: Vec<CodeCave>,
+}
+
+/// A replacement which was moved into a code cave. The code at `site` jumps to `cave` which
+/// contains the replacement followed by a jump back to the end of `site`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trampoline {
+    pub site: Range<u64>,
+    pub cave: Range<u64>,
+}
+
+impl CodeCaves {
+    pub fn find(spans: &[Span]) -> CodeCaves {
+        let mut caves = Vec::new();
+        for (span_index, span) in spans.iter().enumerate() {
+            let mapped = &span.code[..span.virtual_size.min(span.code.len())];
+            let mut run_start = None;
+            for (i, byte) in mapped.iter().chain(Some(&0)).enumerate() {
+                match (*byte == 0xCC, run_start) {
+                    (true, None) => run_start = Some(i),
+                    (false, Some(start)) => {
+                        // Keep the first int3 as it may be intentional (e.g. after a call to a
+                        // function which doesn't return)
+                        if i - start >= MIN_INT3_RUN {
+                            caves.push(CodeCave {
+                                span: span_index,
+                                free: start + 1..i,
+                            });
+                        }
+                        run_start = None;
+                    }
+                    _ => {}
+                }
+            }
+
+            let slack = span.virtual_size..span.max_virtual_size;
+            if !slack.is_empty()
+                && span.code[slack.clone()]
+                    .iter()
+                    .all(|byte| *byte == 0x00 || *byte == 0xCC)
+            {
+                caves.push(CodeCave {
+                    span: span_index,
+                    free: slack,
+                });
+            }
+        }
+        CodeCaves { caves }
+    }
+
+    /// Total number of free bytes
+    pub fn free(&self) -> usize {
+        self.caves.iter().map(|cave| cave.free.len()).sum()
+    }
+
+    /// Replaces the code at `site` in the span with index `span_index` with a jump to a code cave
+    /// which contains `replacement_asm` followed by a jump back to the end of `site`.
+    pub fn place(
+        &mut self,
+        spans: &mut [Span],
+        span_index: usize,
+        site: Range<usize>,
+        replacement_asm: &str,
+        filler_set: &FillerSet,
+    ) -> Result<Trampoline, CodeCaveError> {
+        /// Length of `jmp rel32`
+        const JMP_LENGTH: usize = 5;
+
+        let site_start = (spans[span_index].vaddr + site.start) as u64;
+        let site_end = (spans[span_index].vaddr + site.end) as u64;
+        if site.len() < JMP_LENGTH {
+            return Err(CodeCaveError::SiteTooSmall);
+        }
+
+        // The length may differ once the replacement is assembled at the cave's address
+        let estimated_length = nasm_assemble(replacement_asm, site_start)
+            .map_err(|_| CodeCaveError::AssemblyFailed)?
+            .len()
+            + JMP_LENGTH;
+        // Caves are tried in order until the replacement fits once it's assembled at the cave
+        let mut placement = None;
+        let mut required = estimated_length;
+        for (cave_index, cave) in self.caves.iter().enumerate() {
+            if cave.free.len() < estimated_length {
+                continue;
+            }
+            let cave_start = (spans[cave.span].vaddr + cave.free.start) as u64;
+            let mut relocated = nasm_assemble(replacement_asm, cave_start)
+                .map_err(|_| CodeCaveError::AssemblyFailed)?;
+            let jmp_back = nasm_assemble(
+                &format!("jmp 0x{:x}", site_end),
+                cave_start + relocated.len() as u64,
+            )
+            .map_err(|_| CodeCaveError::AssemblyFailed)?;
+            relocated.extend(jmp_back);
+            if relocated.len() <= cave.free.len() {
+                placement = Some((cave_index, cave_start, relocated));
+                break;
+            }
+            required = relocated.len();
+        }
+        let (cave_index, cave_start, relocated) =
+            placement.ok_or(CodeCaveError::NoSpace(required))?;
+        let cave = self.caves[cave_index].clone();
+
+        let mut jmp_to_cave = nasm_assemble(&format!("jmp 0x{:x}", cave_start), site_start)
+            .map_err(|_| CodeCaveError::AssemblyFailed)?;
+        if jmp_to_cave.len() > site.len() {
+            return Err(CodeCaveError::SiteTooSmall);
+        }
+        jmp_to_cave.extend(filler_set.pad(site.len() - jmp_to_cave.len()));
+
+        let cave_span = &mut spans[cave.span];
+        let cave_end = cave.free.start + relocated.len();
+        cave_span.code[cave.free.start..cave_end].copy_from_slice(&relocated);
+        cave_span.virtual_size = cave_span.virtual_size.max(cave_end);
+        spans[span_index].code.splice(site, jmp_to_cave);
+        self.caves[cave_index].free.start = cave_end;
+
+        Ok(Trampoline {
+            site: site_start..site_end,
+            cave: cave_start..cave_start + relocated.len() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_code_caves() {
+        let mut code = vec![0x90; 64];
+        code[8..40].iter_mut().for_each(|byte| *byte = 0xCC);
+        code[44..48].iter_mut().for_each(|byte| *byte = 0xCC);
+        code[56..].iter_mut().for_each(|byte| *byte = 0x00);
+        let span = Span {
+            virtual_size: 56,
+            ..Span::test(code)
+        };
+        let caves = CodeCaves::find(&[span]);
+        assert_eq!(
+            caves
+                .caves
+                .iter()
+                .map(|cave| cave.free.clone())
+                .collect::<Vec<_>>(),
+            vec![9..40, 56..64]
+        );
+        assert_eq!(caves.free(), 39);
+    }
+}
@@ -0,0 +1,204 @@
+use fxhash::FxHashSet;
+use log::*;
+
+use crate::binary::Span;
+use crate::cfg::ControlFlowGraph;
+use crate::disassembly::DecodedInstruction;
+use crate::pattern::FillerSet;
+
+/// Result of compacting a span
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Old and new address of every moved instruction
+    pub moved: Vec<(u64, u64)>,
+    pub compacted_blocks: usize,
+    /// Blocks which contained an instruction which couldn't be relocated
+    pub aborted_blocks: usize,
+}
+
+/// Returns all addresses which may be the target of a branch: direct branch targets and
+/// RIP-relative references (which may be used as indirect branch targets)
+pub fn referenced_addresses(instructions: &[DecodedInstruction]) -> FxHashSet<u64> {
+    instructions
+        .iter()
+        .filter_map(|instruction| {
+            instruction
+                .branch_target()
+                .or_else(|| instruction.rip_relative_target())
+        })
+        .collect()
+}
+
+/// Moves the filler inside of each basic block of the control-flow graph which lies in the span to
+/// the block's end so the instructions are contiguous. Only code recovered by `cfg` is touched (data
+/// in between is never mistaken for code) and blocks are additionally split at referenced addresses
+/// (`leaders`). The start of a block is never moved so branches into the span stay valid; relative
+/// branches and RIP-relative operands of the moved instructions are adjusted. Blocks are left as
+/// they are if an adjusted operand doesn't fit its encoding anymore.
+pub fn compact(
+    span: &mut Span,
+    cfg: &ControlFlowGraph,
+    leaders: &FxHashSet<u64>,
+    filler_set: &FillerSet,
+) -> CompactionReport {
+    let mapped = (span.vaddr as u64)..(span.vaddr + span.virtual_size.min(span.code.len())) as u64;
+    let mut blocks: Vec<Vec<&DecodedInstruction>> = Vec::new();
+    for basic_block in cfg
+        .blocks()
+        .filter(|block| mapped.start <= block.start() && block.end() <= mapped.end)
+    {
+        let mut block: Vec<&DecodedInstruction> = Vec::new();
+        for instruction in cfg
+            .instructions()
+            .range(basic_block.range())
+            .map(|(_, ins)| ins)
+        {
+            if !block.is_empty() && leaders.contains(&instruction.address()) {
+                blocks.push(block);
+                block = Vec::new();
+            }
+            block.push(instruction);
+        }
+        blocks.push(block);
+    }
+
+    let mut report = CompactionReport::default();
+    for block in blocks {
+        let is_filler = |instruction: &&DecodedInstruction| {
+            filler_set
+                .fillers()
+                .iter()
+                .any(|filler| &filler[..] == instruction.bytes())
+        };
+        // Nothing to do if all of the filler is already at the end
+        let last_instruction = match block.iter().rposition(|ins| !is_filler(ins)) {
+            Some(last_instruction) => last_instruction,
+            None => continue,
+        };
+        if !block[..last_instruction].iter().any(&is_filler) {
+            continue;
+        }
+
+        let block_address = block[0].address();
+        let block_size = (block[block.len() - 1].end() - block_address) as usize;
+        let mut compacted = Vec::with_capacity(block_size);
+        let mut moved = Vec::new();
+        let mut aborted = false;
+        for instruction in block.iter().filter(|ins| !is_filler(ins)) {
+            let new_address = block_address + compacted.len() as u64;
+            match relocate(instruction, new_address) {
+                Some(bytes) => compacted.extend(bytes),
+                None => {
+                    debug!(
+                        "Can't relocate {} from 0x{:x} to 0x{:x}",
+                        instruction,
+                        instruction.address(),
+                        new_address
+                    );
+                    aborted = true;
+                    break;
+                }
+            }
+            if new_address != instruction.address() {
+                moved.push((instruction.address(), new_address));
+            }
+        }
+        if aborted {
+            report.aborted_blocks += 1;
+            continue;
+        }
+
+        compacted.extend(filler_set.pad(block_size - compacted.len()));
+        let offset = block_address as usize - span.vaddr;
+        span.code[offset..offset + block_size].copy_from_slice(&compacted);
+        report.compacted_blocks += 1;
+        report.moved.extend(moved);
+    }
+    report
+}
+
+/// Returns the encoding of `instruction` when it's located at `address`. Branch targets and
+/// RIP-relative references keep pointing to the same address.
+fn relocate(instruction: &DecodedInstruction, address: u64) -> Option<Vec<u8>> {
+    match instruction
+        .branch_target()
+        .or_else(|| instruction.rip_relative_target())
+    {
+        Some(target) => instruction.encode_at(address, target),
+        None => Some(instruction.bytes().to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembly::Disassembler;
+
+    /// Compacts the code at 0x1000 with additional leaders (e.g. relocation targets)
+    fn compact_code(code: Vec<u8>, extra_leaders: &[u64]) -> (Vec<u8>, CompactionReport) {
+        let mut span = Span::test(code);
+        let disassembler = Disassembler::new();
+        let cfg = ControlFlowGraph::recover(&[span.clone()], &[0x1000], &disassembler);
+        let mut leaders = referenced_addresses(&disassembler.linear_sweep(&span.code, 0x1000));
+        leaders.extend(extra_leaders);
+        let report = compact(&mut span, &cfg, &leaders, &FillerSet::default());
+        (span.code, report)
+    }
+
+    #[test]
+    fn compact_block() {
+        let (code, report) = compact_code(
+            vec![
+                0x53, // push rbx
+                0x90, // nop
+                0x48, 0x8D, 0x05, 0x10, 0x00, 0x00, 0x00, // lea rax, [rip + 0x10]
+                0x66, 0x90, // nop
+                0xEB, 0x00, // jmp 0x100d
+                0xC3, // ret
+            ],
+            &[],
+        );
+        assert_eq!(
+            code,
+            vec![
+                0x53, // push rbx
+                0x48, 0x8D, 0x05, 0x11, 0x00, 0x00, 0x00, // lea rax, [rip + 0x11]
+                0xEB, 0x03, // jmp 0x100d
+                0x0F, 0x1F, 0x00, // nop dword ptr [rax]
+                0xC3, // ret
+            ]
+        );
+        assert_eq!(report.moved, vec![(0x1002, 0x1001), (0x100B, 0x1008)]);
+        assert_eq!(report.compacted_blocks, 1);
+    }
+
+    #[test]
+    fn data_after_ret_is_untouched() {
+        let data = vec![
+            0x53, // push rbx
+            0x90, // nop
+            0x5B, // pop rbx
+        ];
+        let mut code = vec![0xC3]; // ret
+        code.extend(&data);
+        let (compacted, report) = compact_code(code.clone(), &[]);
+        assert_eq!(compacted, code);
+        assert_eq!(report, CompactionReport::default());
+    }
+
+    #[test]
+    fn relocation_target_isnt_moved() {
+        let code = vec![
+            0x53, // push rbx
+            0x90, // nop
+            0x5B, // pop rbx (e.g. a jump table entry points here)
+            0xC3, // ret
+        ];
+        let (compacted, report) = compact_code(code.clone(), &[0x1002]);
+        assert_eq!(compacted, code);
+        assert_eq!(report, CompactionReport::default());
+        // Without the leader the `pop` would be moved
+        let (_, report) = compact_code(code, &[]);
+        assert_eq!(report.moved, vec![(0x1002, 0x1001), (0x1003, 0x1002)]);
+    }
+}
@@ -0,0 +1,214 @@
+//! Resolution of overlapping matches when all patterns are matched in a single scan instead of
+//! being applied one after the other
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+use fxhash::FxHashMap;
+
+use crate::pattern::PatternMatch;
+
+/// Decides which of two overlapping matches is replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The match which replaces more code wins
+    Longest,
+    /// The match of the pattern with the higher `priority` wins; ties are broken like `Longest`
+    Priority,
+    /// The match of the pattern which comes first in the database wins
+    Order,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<ConflictPolicy, String> {
+        match policy {
+            "longest" => Ok(ConflictPolicy::Longest),
+            "priority" => Ok(ConflictPolicy::Priority),
+            "order" => Ok(ConflictPolicy::Order),
+            _ => Err(format!(
+                "unknown conflict policy {} (expected longest, priority or order)",
+                policy
+            )),
+        }
+    }
+}
+
+/// Match of one of the patterns of a scan
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// Position of the pattern in the order the patterns are applied
+    pub pattern: usize,
+    pub priority: i32,
+    pub span_index: usize,
+    pub pattern_match: PatternMatch,
+}
+
+impl Candidate {
+    fn len(&self) -> usize {
+        self.pattern_match.end() - self.pattern_match.start()
+    }
+}
+
+/// Outcome of resolving the conflicts between candidates
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolution {
+    /// Candidates which were replaced ordered by span and address
+    pub chosen: Vec<Candidate>,
+    /// Candidates which lost a conflict
+    pub dropped: Vec<Candidate>,
+}
+
+/// Replaces candidates which don't conflict with each other. The candidates are considered from
+/// best to worst according to `policy` and are dropped if the code they replace overlaps the code
+/// replaced by or a block matched by an already chosen candidate (or vice versa). Otherwise `apply`
+/// tries to replace the candidate; if it fails (e.g. because the match contains a branch target)
+/// the code is left to the next-best candidates.
+pub fn resolve<F>(
+    mut candidates: Vec<Candidate>,
+    policy: ConflictPolicy,
+    mut apply: F,
+) -> Resolution
+where
+    F: FnMut(&Candidate) -> bool,
+{
+    candidates.sort_by(|a, b| {
+        let order = (a.pattern, a.span_index, a.pattern_match.start()).cmp(&(
+            b.pattern,
+            b.span_index,
+            b.pattern_match.start(),
+        ));
+        match policy {
+            ConflictPolicy::Longest => b.len().cmp(&a.len()).then(order),
+            ConflictPolicy::Priority => b
+                .priority
+                .cmp(&a.priority)
+                .then(b.len().cmp(&a.len()))
+                .then(order),
+            ConflictPolicy::Order => order,
+        }
+    });
+
+    // Replaced ranges (which don't overlap) by their start and matched blocks per span
+    let mut replaced: FxHashMap<usize, BTreeMap<usize, usize>> = FxHashMap::default();
+    let mut blocks: FxHashMap<usize, Vec<Range<usize>>> = FxHashMap::default();
+    let mut chosen = Vec::new();
+    let mut dropped = Vec::new();
+    for candidate in candidates {
+        let replaced = replaced.entry(candidate.span_index).or_default();
+        let blocks = blocks.entry(candidate.span_index).or_default();
+        let range = candidate.pattern_match.range();
+        let conflicts = overlaps_replaced(replaced, &range)
+            || candidate
+                .pattern_match
+                .blocks()
+                .iter()
+                .any(|(_, block)| overlaps_replaced(replaced, block))
+            || blocks.iter().any(|block| overlap(block, &range));
+        if conflicts {
+            dropped.push(candidate);
+            continue;
+        }
+        if !apply(&candidate) {
+            continue;
+        }
+        replaced.insert(range.start, range.end);
+        blocks.extend(
+            candidate
+                .pattern_match
+                .blocks()
+                .iter()
+                .map(|(_, block)| block.clone()),
+        );
+        chosen.push(candidate);
+    }
+    chosen.sort_by_key(|candidate| (candidate.span_index, candidate.pattern_match.start()));
+    Resolution { chosen, dropped }
+}
+
+fn overlaps_replaced(replaced: &BTreeMap<usize, usize>, range: &Range<usize>) -> bool {
+    // The ranges don't overlap so only the last one which starts before the end can overlap
+    match replaced.range(..range.end).next_back() {
+        Some((_, &end)) => end > range.start,
+        None => false,
+    }
+}
+
+fn overlap(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::{ObfuscationPatternMatcher, PatternElement};
+
+    fn candidates() -> Vec<Candidate> {
+        // push rax; pop rax; push rax; pop rax
+        let code = [0x50, 0x58, 0x50, 0x58];
+        let patterns = [
+            (vec!["push rax", "pop rax"], 0),
+            (vec!["push rax", "pop rax", "push rax"], -1),
+        ];
+        let mut candidates = Vec::new();
+        for (i, (instructions, priority)) in patterns.iter().enumerate() {
+            let matcher = ObfuscationPatternMatcher::new(
+                instructions
+                    .iter()
+                    .map(|instruction| instruction.parse().unwrap())
+                    .collect::<Vec<PatternElement>>(),
+            )
+            .unwrap();
+            for pattern_match in matcher.match_against(&code) {
+                candidates.push(Candidate {
+                    pattern: i,
+                    priority: *priority,
+                    span_index: 0,
+                    pattern_match,
+                });
+            }
+        }
+        candidates
+    }
+
+    fn chosen(policy: ConflictPolicy) -> Vec<(usize, usize)> {
+        positions(&resolve(candidates(), policy, |_| true).chosen)
+    }
+
+    fn positions(candidates: &[Candidate]) -> Vec<(usize, usize)> {
+        candidates
+            .iter()
+            .map(|candidate| (candidate.pattern, candidate.pattern_match.start()))
+            .collect()
+    }
+
+    #[test]
+    fn resolve_conflicts() {
+        assert_eq!(candidates().len(), 3);
+        assert_eq!(chosen(ConflictPolicy::Order), vec![(0, 0), (0, 2)]);
+        assert_eq!(chosen(ConflictPolicy::Longest), vec![(1, 0)]);
+        assert_eq!(chosen(ConflictPolicy::Priority), vec![(0, 0), (0, 2)]);
+        assert_eq!(
+            resolve(candidates(), ConflictPolicy::Longest, |_| true)
+                .dropped
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn fall_back_when_longest_is_unsafe() {
+        // A branch target at offset 2 is inside of the longest match but not inside of the others
+        let mut tried = Vec::new();
+        let resolution = resolve(candidates(), ConflictPolicy::Longest, |candidate| {
+            tried.push((candidate.pattern, candidate.pattern_match.start()));
+            let range = candidate.pattern_match.range();
+            !(range.start < 2 && 2 < range.end)
+        });
+        assert_eq!(tried, vec![(1, 0), (0, 0), (0, 2)]);
+        assert_eq!(positions(&resolution.chosen), vec![(0, 0), (0, 2)]);
+        assert!(resolution.dropped.is_empty());
+    }
+}
@@ -0,0 +1,407 @@
+use std::fmt::{self, Display};
+
+use byteorder::{ByteOrder, LittleEndian};
+use capstone::arch::{BuildsCapstone, BuildsCapstoneSyntax};
+use capstone::prelude::*;
+use fxhash::FxHashSet;
+
+use crate::x86::{Flag, Gpr, Location};
+
+/// Maximum length of an x86-64 instruction in bytes
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// Instructions which overwrite their first operand without reading it
+const WRITE_ONLY: &[&str] = &["mov", "movabs", "movzx", "movsx", "movsxd", "lea", "pop"];
+/// Instructions which read and overwrite their first operand
+const READ_MODIFY_WRITE: &[&str] = &[
+    "add", "sub", "and", "or", "xor", "adc", "sbb", "inc", "dec", "neg", "not", "imul", "shl",
+    "shr", "sar", "rol", "ror", "bswap",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    address: u64,
+    bytes: Vec<u8>,
+    mnemonic: String,
+    operands: String,
+}
+
+impl DecodedInstruction {
+    fn new(
+        address: u64,
+        bytes: &[u8],
+        mnemonic: Option<&str>,
+        operands: Option<&str>,
+    ) -> DecodedInstruction {
+        DecodedInstruction {
+            address,
+            bytes: bytes.to_vec(),
+            mnemonic: mnemonic.unwrap_or("").to_string(),
+            operands: operands.unwrap_or("").to_string(),
+        }
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Address of the following instruction
+    pub fn end(&self) -> u64 {
+        self.address + self.bytes.len() as u64
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn mnemonic(&self) -> &str {
+        &self.mnemonic
+    }
+
+    pub fn operands(&self) -> &str {
+        &self.operands
+    }
+
+    /// Whether the instruction (potentially) continues execution somewhere else than at the
+    /// following instruction
+    pub fn is_control_transfer(&self) -> bool {
+        let mnemonic = self.mnemonic.as_str();
+        mnemonic.starts_with('j')
+            || mnemonic.starts_with("loop")
+            || mnemonic.starts_with("call")
+            || mnemonic.starts_with("ret")
+            || mnemonic.starts_with("iret")
+            || mnemonic.starts_with("int")
+            || mnemonic == "syscall"
+            || mnemonic == "ud2"
+            || mnemonic == "hlt"
+    }
+
+    /// Whether execution may continue at the following instruction
+    pub fn falls_through(&self) -> bool {
+        let mnemonic = self.mnemonic.as_str();
+        !(mnemonic == "jmp"
+            || mnemonic.starts_with("ret")
+            || mnemonic.starts_with("iret")
+            || mnemonic == "int3"
+            || mnemonic == "ud2"
+            || mnemonic == "hlt")
+    }
+
+    /// Target of a direct branch (i.e. a branch with an immediate operand)
+    pub fn branch_target(&self) -> Option<u64> {
+        if !self.is_control_transfer() {
+            return None;
+        }
+        parse_number(&self.operands)
+    }
+
+    /// Displacement of a RIP-relative memory operand
+    pub fn rip_displacement(&self) -> Option<i64> {
+        let index = self.operands.find("rip")?;
+        let rest = self.operands[index + 3..].trim_start();
+        if rest.starts_with(']') {
+            return Some(0);
+        }
+        let negative = rest.starts_with('-');
+        if !negative && !rest.starts_with('+') {
+            return None;
+        }
+        let end = rest.find(']')?;
+        let displacement = parse_number(rest[1..end].trim())? as i64;
+        Some(if negative {
+            -displacement
+        } else {
+            displacement
+        })
+    }
+
+    /// Address referenced by a RIP-relative memory operand
+    pub fn rip_relative_target(&self) -> Option<u64> {
+        self.rip_displacement()
+            .map(|displacement| self.end().wrapping_add(displacement as u64))
+    }
+
+    /// Returns the encoding of the instruction when it's located at `address` and its branch target
+    /// or RIP-relative reference is `target`. Fails if the instruction has neither or the relative
+    /// operand doesn't fit into the encoding.
+    pub fn encode_at(&self, address: u64, target: u64) -> Option<Vec<u8>> {
+        let mut bytes = self.bytes.clone();
+        let size = bytes.len();
+        let end = address + size as u64;
+
+        if let Some(old_target) = self.branch_target() {
+            let old_relative = old_target.wrapping_sub(self.end()) as i64;
+            let new_relative = target.wrapping_sub(end) as i64;
+            // The relative target is always the last operand; branches with a 32-bit operand are
+            // at least 5 bytes long
+            if size < 5 && i64::from(bytes[size - 1] as i8) == old_relative {
+                if new_relative < i64::from(i8::min_value())
+                    || new_relative > i64::from(i8::max_value())
+                {
+                    return None;
+                }
+                bytes[size - 1] = new_relative as i8 as u8;
+            } else if size >= 5
+                && i64::from(LittleEndian::read_i32(&bytes[size - 4..])) == old_relative
+            {
+                LittleEndian::write_i32(&mut bytes[size - 4..], checked_i32(new_relative)?);
+            } else {
+                return None;
+            }
+        } else {
+            // The displacement is located by its value; give up if that's ambiguous
+            let displacement = self.rip_displacement()?;
+            let mut encoded = [0; 4];
+            LittleEndian::write_i32(&mut encoded, checked_i32(displacement)?);
+            let mut positions =
+                (0..=size.saturating_sub(4)).filter(|&i| bytes[i..].starts_with(&encoded));
+            let position = positions.next()?;
+            if positions.next().is_some() {
+                return None;
+            }
+            LittleEndian::write_i32(
+                &mut bytes[position..position + 4],
+                checked_i32(target.wrapping_sub(end) as i64)?,
+            );
+        }
+        Some(bytes)
+    }
+
+    /// Returns all general-purpose registers which are read or written by the instruction, either
+    /// explicitly through an operand or implicitly
+    pub fn touched_registers(&self) -> FxHashSet<Gpr> {
+        let mut registers: FxHashSet<Gpr> = self
+            .operands
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter_map(Gpr::from_register_name)
+            .collect();
+
+        // The last word is the actual mnemonic (e.g. for `rep stosb`)
+        let prefixed = self.mnemonic.contains(' ');
+        let mnemonic = self.mnemonic.rsplit(' ').next().unwrap_or("");
+        let operand_count = if self.operands.is_empty() {
+            0
+        } else {
+            self.operands.split(',').count()
+        };
+        let implicit: &[Gpr] = match mnemonic {
+            "push" | "pop" | "pushfq" | "popfq" | "call" | "ret" | "retn" => &[Gpr::Rsp],
+            "enter" | "leave" => &[Gpr::Rsp, Gpr::Rbp],
+            "mul" | "div" | "idiv" | "cwd" | "cdq" | "cqo" | "rdtsc" => &[Gpr::Rax, Gpr::Rdx],
+            "imul" if operand_count == 1 => &[Gpr::Rax, Gpr::Rdx],
+            "cbw" | "cwde" | "cdqe" | "lahf" | "sahf" | "cmpxchg" | "xlatb" => &[Gpr::Rax],
+            "cpuid" => &[Gpr::Rax, Gpr::Rbx, Gpr::Rcx, Gpr::Rdx],
+            "syscall" => &[Gpr::Rax, Gpr::Rcx, Gpr::R11],
+            "jrcxz" | "jecxz" | "loop" | "loope" | "loopne" => &[Gpr::Rcx],
+            _ if mnemonic.starts_with("movs")
+                || mnemonic.starts_with("stos")
+                || mnemonic.starts_with("lods")
+                || mnemonic.starts_with("scas")
+                || mnemonic.starts_with("cmps") =>
+            {
+                &[Gpr::Rax, Gpr::Rcx, Gpr::Rsi, Gpr::Rdi]
+            }
+            _ => &[],
+        };
+        registers.extend(implicit.iter().cloned());
+        if prefixed && self.mnemonic.starts_with("rep") {
+            registers.insert(Gpr::Rcx);
+        }
+        registers
+    }
+
+    /// Registers and flags whose value the instruction may depend on. Unknown instructions are
+    /// assumed to read all flags.
+    pub fn reads(&self) -> FxHashSet<Location> {
+        let mnemonic = self.mnemonic.rsplit(' ').next().unwrap_or("");
+        let operands = self.operand_list();
+        let mut reads: FxHashSet<Location> = self
+            .touched_registers()
+            .into_iter()
+            .map(Location::Register)
+            .collect();
+        // A full register which is only written isn't read
+        let write_only = WRITE_ONLY.contains(&self.mnemonic.as_str());
+        if let (true, Some(Location::Register(register))) = (write_only, self.written_register()) {
+            let read_elsewhere = operands[1..].iter().any(|operand| {
+                operand
+                    .split(|c: char| !c.is_ascii_alphanumeric())
+                    .any(|name| Gpr::from_register_name(name) == Some(register))
+            });
+            if !read_elsewhere {
+                reads.remove(&Location::Register(register));
+            }
+        }
+
+        match flag_effect(mnemonic) {
+            FlagEffect::Reads(flags) => reads.extend(flags.iter().cloned().map(Location::Flag)),
+            FlagEffect::Writes(_) | FlagEffect::None => {}
+            FlagEffect::Unknown => reads.extend(Location::flags()),
+        }
+        reads
+    }
+
+    /// Registers and flags which the instruction certainly overwrites completely
+    pub fn writes(&self) -> FxHashSet<Location> {
+        let mnemonic = self.mnemonic.rsplit(' ').next().unwrap_or("");
+        let mut writes = FxHashSet::default();
+        writes.extend(self.written_register());
+        if let FlagEffect::Writes(flags) = flag_effect(mnemonic) {
+            writes.extend(flags.iter().cloned().map(Location::Flag));
+        }
+        writes
+    }
+
+    /// The full register which is the destination of the instruction. Writes to 8- and 16-bit
+    /// sub-registers keep the remaining bits and aren't considered.
+    fn written_register(&self) -> Option<Location> {
+        let mnemonic = self.mnemonic.as_str();
+        if !WRITE_ONLY.contains(&mnemonic) && !READ_MODIFY_WRITE.contains(&mnemonic) {
+            return None;
+        }
+        let destination = self.operand_list()[0];
+        let is_full_register = destination.starts_with('r')
+            && !destination.ends_with(&['w', 'b'][..])
+            || destination.starts_with('e');
+        match Gpr::from_register_name(destination) {
+            Some(register) if is_full_register => Some(Location::Register(register)),
+            _ => None,
+        }
+    }
+
+    fn operand_list(&self) -> Vec<&str> {
+        self.operands.split(',').map(str::trim).collect()
+    }
+}
+
+/// How an instruction affects the status flags
+enum FlagEffect {
+    None,
+    Reads(&'static [Flag]),
+    Writes(&'static [Flag]),
+    Unknown,
+}
+
+fn flag_effect(mnemonic: &str) -> FlagEffect {
+    const ALL: &[Flag] = &[Flag::Cf, Flag::Pf, Flag::Af, Flag::Zf, Flag::Sf, Flag::Of];
+    const ALL_BUT_CF: &[Flag] = &[Flag::Pf, Flag::Af, Flag::Zf, Flag::Sf, Flag::Of];
+    match mnemonic {
+        "add" | "sub" | "cmp" | "test" | "and" | "or" | "xor" | "neg" | "mul" | "imul" => {
+            FlagEffect::Writes(ALL)
+        }
+        "inc" | "dec" => FlagEffect::Writes(ALL_BUT_CF),
+        // Shifts and rotates by zero don't modify the flags
+        "mov" | "movabs" | "movzx" | "movsx" | "movsxd" | "lea" | "push" | "pop" | "xchg"
+        | "nop" | "not" | "bswap" | "shl" | "shr" | "sar" | "rol" | "ror" | "cdqe" | "cqo"
+        | "jmp" | "call" | "ret" | "int3" => FlagEffect::None,
+        "adc" | "sbb" | "rcl" | "rcr" | "cmc" => FlagEffect::Reads(&[Flag::Cf]),
+        _ if mnemonic.starts_with('j')
+            || mnemonic.starts_with("set")
+            || mnemonic.starts_with("cmov")
+            || mnemonic.starts_with("pushf")
+            || mnemonic == "lahf" =>
+        {
+            FlagEffect::Reads(ALL)
+        }
+        _ => FlagEffect::Unknown,
+    }
+}
+
+fn checked_i32(n: i64) -> Option<i32> {
+    if n < i64::from(i32::min_value()) || n > i64::from(i32::max_value()) {
+        None
+    } else {
+        Some(n as i32)
+    }
+}
+
+/// Parses a number as printed by Capstone (hexadecimal with a `0x` prefix or decimal)
+pub(crate) fn parse_number(number: &str) -> Option<u64> {
+    if number.starts_with("0x") {
+        u64::from_str_radix(&number[2..], 16).ok()
+    } else {
+        number.parse().ok()
+    }
+}
+
+impl Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+pub struct Disassembler {
+    capstone: Capstone,
+}
+
+impl Disassembler {
+    pub fn new() -> Disassembler {
+        let capstone = Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .syntax(arch::x86::ArchSyntax::Intel)
+            .detail(false)
+            .build()
+            .expect("Failed to initialize Capstone engine");
+        Disassembler { capstone }
+    }
+
+    /// Decodes the instruction at the start of `bytes` which is located at `address`
+    pub fn decode(&self, bytes: &[u8], address: u64) -> Option<DecodedInstruction> {
+        let bytes = &bytes[..bytes.len().min(MAX_INSTRUCTION_LENGTH)];
+        let instructions = self.capstone.disasm_count(bytes, address, 1).ok()?;
+        let instruction = instructions.iter().next()?;
+        Some(DecodedInstruction::new(
+            instruction.address(),
+            instruction.bytes(),
+            instruction.mnemonic(),
+            instruction.op_str(),
+        ))
+    }
+
+    /// Decodes all of `bytes` from start to end. Bytes which can't be decoded are skipped.
+    pub fn linear_sweep(&self, bytes: &[u8], address: u64) -> Vec<DecodedInstruction> {
+        let mut decoded = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let instructions = match self
+                .capstone
+                .disasm_all(&bytes[offset..], address + offset as u64)
+            {
+                Ok(instructions) => instructions,
+                Err(_) => break,
+            };
+            let mut decoded_any = false;
+            for instruction in instructions.iter() {
+                decoded_any = true;
+                offset += instruction.bytes().len();
+                decoded.push(DecodedInstruction::new(
+                    instruction.address(),
+                    instruction.bytes(),
+                    instruction.mnemonic(),
+                    instruction.op_str(),
+                ));
+            }
+            if !decoded_any {
+                // Capstone stops at the first invalid instruction
+                offset += 1;
+            }
+        }
+        decoded
+    }
+}
+
+impl Default for Disassembler {
+    fn default() -> Disassembler {
+        Disassembler::new()
+    }
+}
@@ -0,0 +1,89 @@
+use failure::Fail;
+
+use crate::disassembly::Disassembler;
+use crate::semantics::{SemanticsError, State, Value};
+
+/// Maximum number of instructions which are executed before giving up (e.g. in a loop)
+const MAX_STEPS: usize = 1_000;
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum EmulationError {
+    #[fail(display = "can't decode instruction at 0x{:x}", _0)]
+    UndecodableInstruction(u64),
+    #[fail(display = "can't execute instruction at 0x{:x}: {}", _0, _1)]
+    Semantics(u64, #[cause] SemanticsError),
+    #[fail(display = "more than {} instructions executed", _0)]
+    StepLimit(usize),
+}
+
+/// Concretely executes `code` located at `address` starting from `state` (usually created with
+/// `State::concrete`) until execution leaves the code, either by falling off its end or by a
+/// control transfer to a target outside of it (or one which isn't known). The returned state's
+/// next instruction is where execution left the code.
+pub fn emulate(
+    code: &[u8],
+    address: u64,
+    mut state: State,
+    disassembler: &Disassembler,
+) -> Result<State, EmulationError> {
+    let end = address + code.len() as u64;
+    let mut current = address;
+    for _ in 0..MAX_STEPS {
+        if current < address || current >= end {
+            state.finish(&Value::constant(current));
+            return Ok(state);
+        }
+        let instruction = disassembler
+            .decode(&code[(current - address) as usize..], current)
+            .ok_or(EmulationError::UndecodableInstruction(current))?;
+        state
+            .execute(
+                &instruction.to_string(),
+                &Value::constant(instruction.end()),
+            )
+            .map_err(|error| EmulationError::Semantics(current, error))?;
+        current = match state.take_next() {
+            Some(next) => match next.as_constant() {
+                Some(target) => target,
+                None => {
+                    state.finish(&next);
+                    return Ok(state);
+                }
+            },
+            None => instruction.end(),
+        };
+    }
+    Err(EmulationError::StepLimit(MAX_STEPS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::{Flag, Gpr};
+
+    #[test]
+    fn emulate_branches() {
+        let code = [
+            0x48, 0x83, 0xE8, 0x01, // sub rax, 1
+            0x75, 0xFA, // jne 0x1000
+            0x48, 0x0F, 0x44, 0xD9, // cmove rbx, rcx
+            0x53, // push rbx
+            0xC3, // ret
+        ];
+        let mut registers = [0; 16];
+        registers[0] = 3;
+        registers[1] = 0x2000;
+        registers[4] = 0x8000;
+        let state = State::concrete(&registers, &[false; 6]);
+
+        let mut state = emulate(&code, 0x1000, state, &Disassembler::new()).unwrap();
+        assert_eq!(state.register(Gpr::Rax).as_constant(), Some(0));
+        assert_eq!(state.register(Gpr::Rbx).as_constant(), Some(0x2000));
+        assert_eq!(state.register(Gpr::Rsp).as_constant(), Some(0x8000));
+        assert_eq!(state.flag(Flag::Zf).as_constant(), Some(1));
+        assert_eq!(
+            state.take_next().and_then(|next| next.as_constant()),
+            Some(0x2000)
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use std::str::FromStr;
+
+use failure::Fail;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::disassembly::parse_number;
+use crate::pattern::*;
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum GeneralizationError {
+    #[fail(display = "no examples given")]
+    NoExamples,
+    #[fail(
+        display = "example {} doesn't have the same number of instructions as the first one",
+        _0
+    )]
+    DifferentLengths(usize),
+    #[fail(
+        display = "instruction `{}` of example {} doesn't have the same structure as in the first one",
+        _1, _0
+    )]
+    DifferentStructure(usize, String),
+    #[fail(
+        display = "`{}` differs between examples but isn't a register or a number",
+        _0
+    )]
+    UnsupportedDifference(String),
+    #[fail(
+        display = "`{}` in the replacement can't be derived from the pattern",
+        _0
+    )]
+    UnderivedReplacementValue(String),
+    #[fail(display = "generalized instruction is invalid: {}", _0)]
+    InvalidInstruction(String),
+}
+
+/// A concrete obfuscated instruction sequence with its clean equivalent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Example {
+    pub obfuscated: Vec<String>,
+    pub clean: Vec<String>,
+}
+
+impl Example {
+    /// Example made of the instructions of a pattern without variables (other elements are
+    /// ignored) and its replacement
+    pub fn from_pattern(pattern: &ObfuscationPattern) -> Example {
+        Example {
+            obfuscated: pattern
+                .pattern()
+                .iter()
+                .filter_map(|element| match element {
+                    PatternElement::Instruction(instruction) => {
+                        Some(instruction.pattern().to_string())
+                    }
+                    _ => None,
+                })
+                .collect(),
+            clean: pattern
+                .replacement()
+                .iter()
+                .map(|instruction| instruction.pattern().to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Kind of the variable a differing token becomes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Register,
+    Number,
+}
+
+/// Token position which differs between the examples
+struct Variable {
+    kind: Kind,
+    /// Value in each example
+    values: Vec<String>,
+    name: String,
+}
+
+/// Infers the most specific pattern which covers all examples: tokens which are the same in all
+/// examples stay as they are; registers and numbers which differ become variables. Tokens share a
+/// variable if they have the same value in every example. Values in the replacement which differ
+/// have to occur in the pattern.
+pub fn generalize(examples: &[Example]) -> Result<ObfuscationPattern, GeneralizationError> {
+    let first = examples.first().ok_or(GeneralizationError::NoExamples)?;
+    for (i, example) in examples.iter().enumerate() {
+        if example.obfuscated.len() != first.obfuscated.len()
+            || example.clean.len() != first.clean.len()
+        {
+            return Err(GeneralizationError::DifferentLengths(i + 1));
+        }
+    }
+
+    let mut variables: Vec<Variable> = Vec::new();
+    let mut generalize_sequence = |sequence: fn(&Example) -> &[String],
+                                   is_replacement: bool|
+     -> Result<Vec<String>, GeneralizationError> {
+        let mut generalized = Vec::new();
+        for (n, instruction) in sequence(first).iter().enumerate() {
+            let tokens = tokenize(instruction);
+            let other_tokens: Vec<_> = examples
+                .iter()
+                .map(|example| tokenize(&sequence(example)[n]))
+                .collect();
+            let mut text = String::new();
+            let mut last_end = 0;
+            for (position, &(start, end)) in tokens.iter().enumerate() {
+                let mut values = Vec::new();
+                for (i, (example, example_tokens)) in examples.iter().zip(&other_tokens).enumerate()
+                {
+                    let example_instruction = &sequence(example)[n];
+                    match example_tokens.get(position) {
+                        Some(&(start, end)) if example_tokens.len() == tokens.len() => {
+                            values.push(example_instruction[start..end].to_string())
+                        }
+                        _ => {
+                            return Err(GeneralizationError::DifferentStructure(
+                                i + 1,
+                                example_instruction.clone(),
+                            ))
+                        }
+                    }
+                }
+                text.push_str(&instruction[last_end..start]);
+                last_end = end;
+
+                let token = &instruction[start..end];
+                if values.iter().all(|value| same_value(value, token)) {
+                    text.push_str(token);
+                    continue;
+                }
+                let kind = match kind(&values) {
+                    Some(kind) if position > 0 => kind,
+                    _ => {
+                        return Err(GeneralizationError::UnsupportedDifference(
+                            token.to_string(),
+                        ))
+                    }
+                };
+                let existing = variables.iter().find(|variable| {
+                    variable.kind == kind
+                        && variable
+                            .values
+                            .iter()
+                            .zip(&values)
+                            .all(|(a, b)| same_value(a, b))
+                });
+                let name = match existing {
+                    Some(variable) => variable.name.clone(),
+                    None if is_replacement => {
+                        return Err(GeneralizationError::UnderivedReplacementValue(
+                            token.to_string(),
+                        ))
+                    }
+                    None => {
+                        let count = variables
+                            .iter()
+                            .filter(|variable| variable.kind == kind)
+                            .count();
+                        let name = match kind {
+                            Kind::Register => format!("$reg:r{}", count + 1),
+                            Kind::Number => format!("$num:n{}", count + 1),
+                        };
+                        variables.push(Variable {
+                            kind,
+                            values,
+                            name: name.clone(),
+                        });
+                        name
+                    }
+                };
+                text.push_str(&name);
+            }
+            text.push_str(&instruction[last_end..]);
+            generalized.push(text);
+        }
+        Ok(generalized)
+    };
+
+    let pattern = generalize_sequence(|example| &example.obfuscated, false)?;
+    let replacement = generalize_sequence(|example| &example.clean, true)?;
+    Ok(ObfuscationPattern::new(
+        pattern
+            .iter()
+            .map(|text| parse(text))
+            .collect::<Result<_, _>>()?,
+        replacement
+            .iter()
+            .map(|text| parse(text))
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+fn parse<T: FromStr<Err = PatternError>>(text: &str) -> Result<T, GeneralizationError> {
+    text.parse()
+        .map_err(|error: PatternError| GeneralizationError::InvalidInstruction(error.to_string()))
+}
+
+/// Byte ranges of the words and punctuation characters of an instruction
+fn tokenize(instruction: &str) -> Vec<(usize, usize)> {
+    lazy_static! {
+        static ref TOKEN: Regex = Regex::new(r"\w+|\S").unwrap();
+    }
+    TOKEN
+        .find_iter(instruction)
+        .map(|token| (token.start(), token.end()))
+        .collect()
+}
+
+fn same_value(a: &str, b: &str) -> bool {
+    match (number(a), number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.eq_ignore_ascii_case(b),
+    }
+}
+
+fn number(token: &str) -> Option<u64> {
+    parse_number(&token.to_ascii_lowercase())
+}
+
+fn kind(values: &[String]) -> Option<Kind> {
+    let is_register = |value: &String| {
+        Register::all()
+            .iter()
+            .any(|register| register.name().eq_ignore_ascii_case(value))
+    };
+    if values.iter().all(is_register) {
+        Some(Kind::Register)
+    } else if values.iter().all(|value| number(value).is_some()) {
+        Some(Kind::Number)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(obfuscated: &[&str], clean: &[&str]) -> Example {
+        Example {
+            obfuscated: obfuscated.iter().map(|s| s.to_string()).collect(),
+            clean: clean.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn generalize_examples() {
+        let pattern = generalize(&[
+            example(
+                &[
+                    "push rbp",
+                    "lea rbp, [rip + 0x1234]",
+                    "xchg rbp, [rsp]",
+                    "ret",
+                ],
+                &["jmp [rip + 0x1234]"],
+            ),
+            example(
+                &[
+                    "push rbx",
+                    "lea rbx, [rip + 0x10]",
+                    "xchg rbx, [rsp]",
+                    "ret",
+                ],
+                &["jmp [rip + 16]"],
+            ),
+        ])
+        .unwrap();
+        assert_eq!(
+            pattern.texts(),
+            (
+                vec![
+                    "push $reg:r1",
+                    "lea $reg:r1, [rip + $num:n1]",
+                    "xchg $reg:r1, [rsp]",
+                    "ret",
+                ],
+                vec!["jmp [rip + $num:n1]"]
+            )
+        );
+
+        // Registers which only happen to be equal in one example get separate variables
+        let pattern = generalize(&[
+            example(&["mov rax, rbx"], &[]),
+            example(&["mov rcx, rcx"], &[]),
+        ])
+        .unwrap();
+        assert_eq!(pattern.texts().0, vec!["mov $reg:r1, $reg:r2"]);
+    }
+
+    #[test]
+    fn generalization_errors() {
+        assert_eq!(generalize(&[]), Err(GeneralizationError::NoExamples));
+        assert_eq!(
+            generalize(&[example(&["push rax"], &[]), example(&["pop rax"], &[])]),
+            Err(GeneralizationError::UnsupportedDifference(
+                "push".to_string()
+            ))
+        );
+        assert_eq!(
+            generalize(&[
+                example(&["mov rax, 1"], &["mov rax, 1"]),
+                example(&["mov rax, 2"], &["mov rax, 3"])
+            ]),
+            Err(GeneralizationError::UnderivedReplacementValue(
+                "1".to_string()
+            ))
+        );
+        assert_eq!(
+            generalize(&[
+                example(&["mov rax, [rsp]"], &[]),
+                example(&["mov rax, [rsp + 8]"], &[])
+            ]),
+            Err(GeneralizationError::DifferentStructure(
+                2,
+                "mov rax, [rsp + 8]".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use fxhash::FxHashMap;
+use log::*;
+
+use crate::binary::{code_at, locate, Span};
+use crate::disassembly::{parse_number, DecodedInstruction, Disassembler};
+use crate::pattern::FillerSet;
+
+/// Maximum number of instructions (including filler) which are decoded for a single stub
+const MAX_STUB_INSTRUCTIONS: usize = 16;
+
+/// Result of simplifying jump chains
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JumpChainReport {
+    /// Address of every retargeted instruction with its old and new target
+    pub retargeted: Vec<(u64, u64, u64)>,
+    /// Referrers which were left as they are because their chain loops
+    pub cycles: usize,
+    /// Referrers whose new target doesn't fit the encoding of the instruction
+    pub failed: usize,
+}
+
+/// Returns the target of the unconditional jump stub at `address`. Stubs are `jmp target`,
+/// `push target; ret` and `push r; lea r, [rip + d]; xchg [rsp], r; ret`; filler may be in
+/// between their instructions.
+pub fn stub_target(
+    spans: &[Span],
+    address: u64,
+    filler_set: &FillerSet,
+    disassembler: &Disassembler,
+) -> Option<u64> {
+    let mut instructions = Vec::new();
+    let mut next = address;
+    for _ in 0..MAX_STUB_INSTRUCTIONS {
+        let instruction = disassembler.decode(code_at(spans, next)?, next)?;
+        next = instruction.end();
+        if filler_set
+            .fillers()
+            .iter()
+            .any(|filler| &filler[..] == instruction.bytes())
+        {
+            continue;
+        }
+        let done = instruction.is_control_transfer();
+        instructions.push(instruction);
+        if done {
+            break;
+        }
+    }
+
+    let mnemonics: Vec<_> = instructions.iter().map(|ins| ins.mnemonic()).collect();
+    match (&mnemonics[..], &instructions[..]) {
+        (["jmp"], [jmp]) => jmp.branch_target(),
+        (["push", "ret"], [push, ret]) if ret.operands().is_empty() => {
+            parse_number(push.operands())
+        }
+        (["push", "lea", "xchg", "ret"], [push, lea, xchg, ret]) if ret.operands().is_empty() => {
+            let register = push.operands();
+            let lea_destination = lea.operands().split(',').next()?.trim();
+            let xchg_operands: Vec<_> = xchg.operands().split(',').map(str::trim).collect();
+            let exchanges_top_of_stack = xchg_operands.len() == 2
+                && xchg_operands.contains(&"qword ptr [rsp]")
+                && xchg_operands.contains(&register);
+            if lea_destination == register && exchanges_top_of_stack {
+                lea.rip_relative_target()
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Retargets the direct branches and `lea`s among `referrers` (ordered by address) which point to a
+/// jump stub to the end of the stub's chain. A `lea` is only retargeted if the loaded address is
+/// only used as a branch target, i.e. it's followed by a `jmp`/`call` to its register or a
+/// `push` of its register and a `ret`. Chains which loop are left as they are.
+pub fn simplify(
+    spans: &mut [Span],
+    referrers: &[DecodedInstruction],
+    filler_set: &FillerSet,
+    disassembler: &Disassembler,
+) -> JumpChainReport {
+    // Final destination of each chain start; `None` if the chain loops
+    let mut resolved: FxHashMap<u64, Option<u64>> = FxHashMap::default();
+    let mut resolve = |start: u64| -> Option<u64> {
+        if let Some(destination) = resolved.get(&start) {
+            return *destination;
+        }
+        let mut chain = vec![start];
+        let mut destination = Some(start);
+        while let Some(next) = stub_target(spans, *chain.last().unwrap(), filler_set, disassembler)
+        {
+            if chain.contains(&next) {
+                destination = None;
+                break;
+            }
+            chain.push(next);
+            destination = Some(next);
+        }
+        resolved.insert(start, destination);
+        destination
+    };
+
+    let mut report = JumpChainReport::default();
+    let mut patches = Vec::new();
+    for (i, referrer) in referrers.iter().enumerate() {
+        let target = match referrer.branch_target() {
+            Some(target) => target,
+            None if referrer.mnemonic() == "lea"
+                && is_branch_target(referrer, &referrers[i + 1..], filler_set) =>
+            {
+                match referrer.rip_relative_target() {
+                    Some(target) => target,
+                    None => continue,
+                }
+            }
+            None => continue,
+        };
+        let destination = match resolve(target) {
+            Some(destination) if destination != target => destination,
+            Some(_) => continue,
+            None => {
+                report.cycles += 1;
+                continue;
+            }
+        };
+        match referrer.encode_at(referrer.address(), destination) {
+            Some(bytes) => {
+                patches.push((referrer.address(), bytes));
+                report
+                    .retargeted
+                    .push((referrer.address(), target, destination));
+            }
+            None => {
+                debug!(
+                    "Can't retarget {} at 0x{:x} to 0x{:x}",
+                    referrer,
+                    referrer.address(),
+                    destination
+                );
+                report.failed += 1;
+            }
+        }
+    }
+
+    // Patch after all chains are resolved as the stubs themselves may be referrers
+    for (address, bytes) in patches {
+        if let Some((span_index, offset)) = locate(spans, address) {
+            spans[span_index].code[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+    }
+    report
+}
+
+/// Whether the address loaded by `lea` is only used as a branch target by the instructions directly
+/// following it
+fn is_branch_target(
+    lea: &DecodedInstruction,
+    following: &[DecodedInstruction],
+    filler_set: &FillerSet,
+) -> bool {
+    let register = match lea.operands().split(',').next() {
+        Some(register) => register.trim(),
+        None => return false,
+    };
+    let mut end = lea.end();
+    let mut uses = Vec::new();
+    for instruction in following {
+        if instruction.address() != end || uses.len() == 2 {
+            break;
+        }
+        end = instruction.end();
+        if !filler_set
+            .fillers()
+            .iter()
+            .any(|filler| &filler[..] == instruction.bytes())
+        {
+            uses.push(instruction);
+        }
+    }
+    match &uses[..] {
+        [branch, ..] if ["jmp", "call"].contains(&branch.mnemonic()) => {
+            branch.operands() == register
+        }
+        [push, ret] => {
+            push.mnemonic() == "push"
+                && push.operands() == register
+                && ret.mnemonic() == "ret"
+                && ret.operands().is_empty()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retarget_chains() {
+        let mut code = vec![
+            0xE9, 0x0B, 0x00, 0x00, 0x00, // jmp 0x1010
+            0xEB, 0x09, // jmp 0x1010
+        ];
+        code.extend(vec![0x90; 9]);
+        code.extend(vec![
+            0x90, // nop
+            0xEB, 0x0D, // jmp 0x1020
+        ]);
+        code.extend(vec![0xCC; 13]);
+        code.extend(vec![
+            0x53, // push rbx
+            0x48, 0x8D, 0x1D, 0x18, 0x00, 0x00, 0x00, // lea rbx, [rip + 0x18]
+            0x48, 0x87, 0x1C, 0x24, // xchg qword ptr [rsp], rbx
+            0xC3, // ret
+        ]);
+        code.extend(vec![0xCC; 19]);
+        code.extend(vec![
+            0xC3, // ret
+            0xEB, 0xFE, // jmp 0x1041
+            0xEB, 0xFC, // jmp 0x1041
+        ]);
+        let mut spans = vec![Span::test(code)];
+
+        let disassembler = Disassembler::new();
+        let referrers = disassembler.linear_sweep(&spans[0].code, 0x1000);
+        let report = simplify(&mut spans, &referrers, &FillerSet::default(), &disassembler);
+        assert_eq!(
+            report.retargeted,
+            vec![
+                (0x1000, 0x1010, 0x1040),
+                (0x1005, 0x1010, 0x1040),
+                (0x1011, 0x1020, 0x1040),
+            ]
+        );
+        assert_eq!(report.cycles, 2);
+        assert_eq!(
+            &spans[0].code[..7],
+            &[0xE9, 0x3B, 0x00, 0x00, 0x00, 0xEB, 0x39]
+        );
+        assert_eq!(&spans[0].code[0x11..0x13], &[0xEB, 0x2D]);
+    }
+
+    #[test]
+    fn retarget_lea_only_used_as_branch_target() {
+        let code = vec![
+            0x48, 0x8D, 0x05, 0x15, 0x00, 0x00, 0x00, // lea rax, [rip + 0x15]
+            0xFF, 0xE0, // jmp rax
+            0x48, 0x8D, 0x1D, 0x0C, 0x00, 0x00, 0x00, // lea rbx, [rip + 0xc]
+            0x48, 0x89, 0xD9, // mov rcx, rbx
+            0x48, 0x8D, 0x15, 0x02, 0x00, 0x00, 0x00, // lea rdx, [rip + 2]
+            0x52, // push rdx
+            0xC3, // ret
+            0xEB, 0x01, // jmp 0x101f
+            0xCC, // int3
+            0xC3, // ret
+        ];
+        let mut spans = vec![Span::test(code)];
+
+        let disassembler = Disassembler::new();
+        let referrers = disassembler.linear_sweep(&spans[0].code, 0x1000);
+        let report = simplify(&mut spans, &referrers, &FillerSet::default(), &disassembler);
+        assert_eq!(
+            report.retargeted,
+            vec![(0x1000, 0x101C, 0x101F), (0x1013, 0x101C, 0x101F)]
+        );
+        assert_eq!(&spans[0].code[3..7], &[0x18, 0x00, 0x00, 0x00]);
+        assert_eq!(&spans[0].code[12..16], &[0x0C, 0x00, 0x00, 0x00]);
+    }
+}
@@ -1,13 +1,30 @@
 #![feature(slice_patterns, nll)]
 #![warn(rust_2018_idioms)]
 
+pub mod binary;
 pub mod byteorder_ext;
+pub mod cfg;
+pub mod code_cave;
+pub mod compaction;
+pub mod conflict;
+pub mod disassembly;
+pub mod emulator;
+pub mod generalize;
+pub mod jump_chain;
+pub mod lint;
+pub mod listing;
+pub mod liveness;
+pub mod mining;
 pub mod pattern;
 pub mod pattern_database;
+pub mod pattern_dsl;
+pub mod rewrite;
+pub mod semantics;
+pub mod verify;
+pub mod x86;
 
 use std::error::Error;
 use std::fmt;
-use std::fs::File;
 use std::io;
 use std::path::Path;
 
@@ -18,17 +35,19 @@ use parking_lot::Mutex;
 
 use pattern_database::PatternDatabase;
 
-pub fn keystone_assemble(assembly: String) -> Result<AsmResult, KeystoneError> {
+/// Assembles `assembly` as if it was located at `address`. This matters for relative branches and
+/// RIP-relative operands which are encoded relative to the address of the next instruction.
+pub fn keystone_assemble(assembly: String, address: u64) -> Result<AsmResult, KeystoneError> {
     lazy_static! {
         static ref KEYSTONE: Mutex<Keystone> = Mutex::new(
             Keystone::new(Arch::X86, Mode::MODE_64).expect("Failed to initialize Keystone engine")
         );
     }
-    KEYSTONE.lock().asm(assembly, 0)
+    KEYSTONE.lock().asm(assembly, address)
 }
 
 // Can't use keystone as it doesn't support NASM syntax: $ (refers to current assembly position)
-pub fn nasm_assemble(asm: &str, _origin: u64) -> Result<Vec<u8>, io::Error> {
+pub fn nasm_assemble(asm: &str, origin: u64) -> Result<Vec<u8>, io::Error> {
     // FIXME: ...
     // use std::io::{Read, Write};
     // use std::process::Command;
@@ -65,7 +84,7 @@ pub fn nasm_assemble(asm: &str, _origin: u64) -> Result<Vec<u8>, io::Error> {
     // }
 
     // FIXME: actually use nasm (need to create temp file as nasm doesn't have a library version?)
-    keystone_assemble(asm.to_string())
+    keystone_assemble(asm.to_string(), origin)
         .map(|result| result.bytes)
         .map_err(|_| io::Error::new(io::ErrorKind::Other, NasmFailed))
 }
@@ -79,12 +98,11 @@ impl fmt::Display for NasmFailed {
     }
 }
 
+/// Loads the pattern database including all files it includes
 pub fn load_pattern_database_from_json<P: AsRef<Path>>(
     path: P,
 ) -> Result<PatternDatabase, Box<dyn Error>> {
-    let file = File::open(path)?;
-    let db = serde_json::from_reader(file)?;
-    Ok(db)
+    PatternDatabase::load(path)
 }
 
 #[cfg(test)]
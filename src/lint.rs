@@ -0,0 +1,429 @@
+//! Checks of a pattern database for patterns which never match, duplicates, patterns which are
+//! made redundant by other ones and replacements which rewrite into each other.
+//!
+//! Patterns are compared by their text: a pattern is more general than another one if its
+//! variables can be instantiated so its instructions become the other one's (whose variables stand
+//! for unknown values). Alternatives and repetitions are expanded; patterns with wildcards and (as
+//! the more general pattern) patterns with blocks or excluded instantiations are only checked for
+//! exact duplicates.
+
+use fxhash::FxHashMap;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::disassembly::{parse_number, Disassembler};
+use crate::pattern::*;
+use crate::pattern_database::{PatternDatabase, PatternFilter};
+use crate::verify::expand;
+
+/// Longest padding which is considered when looking for replacements that rewrite into each other
+/// (the maximum length of an instruction)
+const MAX_PADDING: usize = 15;
+
+/// Problem with one or more patterns; patterns are referred to by their (1-based) index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// The pattern can't be compiled (e.g. because an instruction can't be assembled) so it never
+    /// matches
+    Unmatchable { pattern: usize, reason: String },
+    /// Both patterns match exactly the same code
+    Duplicate {
+        pattern: usize,
+        of: usize,
+        same_replacement: bool,
+    },
+    /// Every match of `pattern` is also a match of the more general pattern `by`
+    Subsumed { pattern: usize, by: usize },
+    /// `by` is applied first and matches a part of `pattern` so replacing its matches may destroy
+    /// the matches of `pattern`
+    Shadowed { pattern: usize, by: usize },
+    /// The replacement of each pattern (with the filler it's padded with) contains a match of the
+    /// next one (and the last one's a match of the first one) so the passes may never stop finding
+    /// matches
+    Cycle(Vec<usize>),
+}
+
+impl Finding {
+    /// Describes the finding using the labels of the patterns in `database`
+    pub fn describe(&self, database: &PatternDatabase) -> String {
+        let label = |n: usize| database.patterns()[n - 1].label(n);
+        match self {
+            Finding::Unmatchable { pattern, reason } => {
+                format!("Pattern {} never matches: {}", label(*pattern), reason)
+            }
+            Finding::Duplicate {
+                pattern,
+                of,
+                same_replacement,
+            } => format!(
+                "Pattern {} is a duplicate of pattern {}{}",
+                label(*pattern),
+                label(*of),
+                if *same_replacement {
+                    ""
+                } else {
+                    " with a different replacement"
+                }
+            ),
+            Finding::Subsumed { pattern, by } => format!(
+                "Pattern {} is subsumed by the more general pattern {}{}",
+                label(*pattern),
+                label(*by),
+                if by < pattern {
+                    " which is applied first"
+                } else {
+                    ""
+                }
+            ),
+            Finding::Shadowed { pattern, by } => format!(
+                "Pattern {} may be shadowed by pattern {} which is applied first and matches a part \
+                 of it",
+                label(*pattern),
+                label(*by)
+            ),
+            Finding::Cycle(patterns) => format!(
+                "The replacements of patterns {} rewrite into each other",
+                patterns
+                    .iter()
+                    .map(|&n| label(n))
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            ),
+        }
+    }
+}
+
+/// Lints the patterns selected by `filter` in the order they're applied
+pub fn lint(
+    database: &PatternDatabase,
+    filter: &PatternFilter,
+    filler_set: &FillerSet,
+) -> Vec<Finding> {
+    let patterns: Vec<_> = database.select(filter).collect();
+    let mut findings = Vec::new();
+    for &(n, pattern) in &patterns {
+        if let Err(error) = ObfuscationPatternMatcher::for_pattern(pattern, filler_set.clone()) {
+            findings.push(Finding::Unmatchable {
+                pattern: n,
+                reason: error.to_string(),
+            });
+        }
+    }
+
+    // Instruction sequences the patterns expand to; `None` if a pattern can't be expanded
+    let expansions: Vec<_> = patterns
+        .iter()
+        .map(|(_, pattern)| expand(pattern.pattern()).ok())
+        .collect();
+    // Whether pattern `general` matches all code which pattern `specific` matches
+    let covers =
+        |general: usize, specific: usize| match (&expansions[general], &expansions[specific]) {
+            (Some(general_expansions), Some(specific_expansions))
+                if is_plain(patterns[general].1) =>
+            {
+                specific_expansions.iter().all(|specific| {
+                    general_expansions
+                        .iter()
+                        .any(|general| instantiates(general, specific))
+                })
+            }
+            _ => false,
+        };
+    // Whether pattern `general` matches a part of some code which pattern `specific` matches
+    let overlaps =
+        |general: usize, specific: usize| match (&expansions[general], &expansions[specific]) {
+            (Some(general_expansions), Some(specific_expansions))
+                if is_plain(patterns[general].1) =>
+            {
+                specific_expansions.iter().any(|specific| {
+                    general_expansions
+                        .iter()
+                        .any(|general| contains(specific, general))
+                })
+            }
+            _ => false,
+        };
+
+    let mut duplicates = vec![false; patterns.len()];
+    for j in 0..patterns.len() {
+        for i in 0..j {
+            if duplicates[i] || duplicates[j] {
+                continue;
+            }
+            let ((n, first), (m, second)) = (patterns[i], patterns[j]);
+            let exact = first.pattern() == second.pattern()
+                && first.blocks() == second.blocks()
+                && first.excluded() == second.excluded();
+            if exact || (first.blocks() == second.blocks() && covers(i, j) && covers(j, i)) {
+                duplicates[j] = true;
+                findings.push(Finding::Duplicate {
+                    pattern: m,
+                    of: n,
+                    same_replacement: first.replacement() == second.replacement(),
+                });
+            } else if covers(i, j) {
+                findings.push(Finding::Subsumed { pattern: m, by: n });
+            } else if covers(j, i) {
+                findings.push(Finding::Subsumed { pattern: n, by: m });
+            } else if overlaps(i, j) {
+                findings.push(Finding::Shadowed { pattern: m, by: n });
+            }
+        }
+    }
+
+    // Replacements which are shorter than their match are padded with filler
+    let disassembler = Disassembler::new();
+    let paddings: Vec<Vec<InstructionPattern>> = (0..=MAX_PADDING)
+        .map(|length| {
+            disassembler
+                .linear_sweep(&filler_set.pad(length), 0)
+                .iter()
+                .filter_map(|instruction| instruction.to_string().parse().ok())
+                .collect()
+        })
+        .collect();
+    // Pattern `i` leads to pattern `j` if its (padded) replacement contains a match of `j`
+    let successors: Vec<Vec<usize>> = patterns
+        .iter()
+        .map(|(_, pattern)| {
+            let padded: Vec<Vec<_>> = paddings
+                .iter()
+                .map(|padding| pattern.replacement().iter().chain(padding).collect())
+                .collect();
+            (0..patterns.len())
+                .filter(|&j| match &expansions[j] {
+                    Some(expansions) if patterns[j].1.blocks().is_empty() => expansions
+                        .iter()
+                        .any(|expansion| padded.iter().any(|code| contains(code, expansion))),
+                    _ => false,
+                })
+                .collect()
+        })
+        .collect();
+    let reachable: Vec<Vec<bool>> = (0..patterns.len())
+        .map(|start| {
+            let mut reachable = vec![false; patterns.len()];
+            let mut stack = successors[start].clone();
+            while let Some(i) = stack.pop() {
+                if !reachable[i] {
+                    reachable[i] = true;
+                    stack.extend(&successors[i]);
+                }
+            }
+            reachable
+        })
+        .collect();
+    let mut in_cycle = vec![false; patterns.len()];
+    for i in 0..patterns.len() {
+        if in_cycle[i] || !reachable[i][i] {
+            continue;
+        }
+        // Strongly connected component of `i`
+        let cycle: Vec<_> = (i..patterns.len())
+            .filter(|&j| reachable[i][j] && reachable[j][i])
+            .collect();
+        for &j in &cycle {
+            in_cycle[j] = true;
+        }
+        findings.push(Finding::Cycle(
+            cycle.into_iter().map(|j| patterns[j].0).collect(),
+        ));
+    }
+    findings
+}
+
+/// Whether the pattern matches every instance of its expansions (i.e. it has no blocks and no
+/// excluded instantiations)
+fn is_plain(pattern: &ObfuscationPattern) -> bool {
+    pattern.blocks().is_empty() && pattern.excluded().is_empty()
+}
+
+/// Whether a part of `code` is an instance of `general`
+fn contains(code: &[&InstructionPattern], general: &[&InstructionPattern]) -> bool {
+    !general.is_empty()
+        && code
+            .windows(general.len())
+            .any(|window| instantiates(general, window))
+}
+
+/// Whether the variables of `general` can be instantiated so it becomes `specific`. The variables
+/// of `specific` stand for unknown values; they can only be matched by variables of the same type.
+fn instantiates(general: &[&InstructionPattern], specific: &[&InstructionPattern]) -> bool {
+    if general.len() != specific.len() {
+        return false;
+    }
+    let mut bindings: FxHashMap<String, String> = FxHashMap::default();
+    general.iter().zip(specific).all(|(general, specific)| {
+        let (general, specific) = (tokenize(general), tokenize(specific));
+        general.len() == specific.len()
+            && general.iter().zip(&specific).all(|(general, specific)| {
+                match variable_type(general) {
+                    Some(typee) => {
+                        can_bind(typee, specific)
+                            && same_token(
+                                bindings
+                                    .entry(general.clone())
+                                    .or_insert_with(|| specific.clone()),
+                                specific,
+                            )
+                    }
+                    None => same_token(general, specific),
+                }
+            })
+    })
+}
+
+/// Variables, words and punctuation characters of an instruction; all but variables are lowercase
+fn tokenize(instruction: &InstructionPattern) -> Vec<String> {
+    lazy_static! {
+        static ref TOKEN: Regex = Regex::new(r"\$\w+:\w+|\w+|\S").unwrap();
+    }
+    TOKEN
+        .find_iter(instruction.pattern())
+        .map(|token| {
+            if token.as_str().starts_with('$') {
+                token.as_str().to_string()
+            } else {
+                token.as_str().to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+fn variable_type(token: &str) -> Option<&str> {
+    if token.starts_with('$') {
+        token[1..].split(':').next()
+    } else {
+        None
+    }
+}
+
+/// Whether a variable of type `typee` can have the value `token`
+fn can_bind(typee: &str, token: &str) -> bool {
+    match variable_type(token) {
+        Some(other) => other == typee,
+        None => match typee {
+            "reg" => Register::all()
+                .iter()
+                .any(|register| register.name().eq_ignore_ascii_case(token)),
+            "num" | "len" | "label" => parse_number(token).is_some(),
+            _ => false,
+        },
+    }
+}
+
+fn same_token(a: &str, b: &str) -> bool {
+    match (parse_number(a), parse_number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern_dsl;
+
+    #[test]
+    fn lint_database() {
+        let database = pattern_dsl::parse(
+            "\
+@id push-pop
+push $reg:r1
+pop $reg:r1
+=>
+
+@id push-pop-copy
+push $reg:other
+pop $reg:other
+=> nop
+
+@id push-pop-rax
+push rax
+pop rax
+=>
+
+@id push-pop-ret
+push $reg:r1
+pop $reg:r1
+ret
+=> ret
+
+@id push-rbx
+push rbx
+=> pop rbx
+
+@id pop-rbx
+pop rbx
+=> push rbx
+
+@id unreferenced-block
+push rcx
+unused:
+pop rcx
+=>
+
+@id nop
+nop
+=>
+
+@id two-numbers
+mov qword ptr [rip + $num:a], $num:b
+=> nop
+",
+        )
+        .unwrap();
+        let findings = lint(&database, &PatternFilter::default(), &FillerSet::default());
+        match &findings[0] {
+            Finding::Unmatchable { pattern: 7, .. } => {}
+            finding => panic!("unexpected finding: {:?}", finding),
+        }
+        assert_eq!(
+            findings[1],
+            Finding::Unmatchable {
+                pattern: 9,
+                reason: "an instruction can't have more than one number variable".to_string()
+            }
+        );
+        assert_eq!(
+            &findings[2..],
+            &[
+                Finding::Duplicate {
+                    pattern: 2,
+                    of: 1,
+                    same_replacement: false
+                },
+                Finding::Subsumed { pattern: 3, by: 1 },
+                Finding::Shadowed { pattern: 4, by: 1 },
+                Finding::Cycle(vec![5, 6]),
+                // The empty replacement is padded with a nop
+                Finding::Cycle(vec![8]),
+            ][..]
+        );
+        assert_eq!(
+            findings[3].describe(&database),
+            "Pattern 3 (push-pop-rax) is subsumed by the more general pattern 1 (push-pop) which is \
+             applied first"
+        );
+    }
+
+    #[test]
+    fn instantiation() {
+        let instructions = |texts: &[&str]| -> Vec<InstructionPattern> {
+            texts.iter().map(|text| text.parse().unwrap()).collect()
+        };
+        let general = instructions(&["lea $reg:a, [rip + $num:n]", "xchg $reg:a, [rsp]"]);
+        let general: Vec<_> = general.iter().collect();
+        let check = |texts: &[&str]| {
+            let specific = instructions(texts);
+            instantiates(&general, &specific.iter().collect::<Vec<_>>())
+        };
+        assert!(check(&["lea rbx, [rip + 0x10]", "xchg rbx, [rsp]"]));
+        assert!(check(&[
+            "lea $reg:r1, [rip + $num:n1]",
+            "XCHG $reg:r1, [rsp]"
+        ]));
+        assert!(!check(&["lea rbx, [rip + 0x10]", "xchg rcx, [rsp]"]));
+        assert!(!check(&["lea rbx, [rip + $reg:r1]", "xchg rbx, [rsp]"]));
+    }
+}
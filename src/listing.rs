@@ -0,0 +1,569 @@
+//! Importer for disassembly listings (IDA Pro and x64dbg style) which turns each snippet into a
+//! draft pattern. Snippets are separated by blank lines or braces; an IDA-style replacement may
+//! follow a snippet after a line starting with `=` and continues until the next blank line.
+
+use std::fmt::Write;
+
+use fxhash::FxHashMap;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::pattern::*;
+use crate::x86::{BranchKind, Gpr};
+
+/// Smaller numbers (e.g. stack offsets) are structural and stay concrete when generalizing
+const MIN_GENERALIZED_NUMBER: u64 = 0x1000;
+
+/// Result of importing a listing
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportReport {
+    /// Disabled draft patterns which have to be reviewed before use
+    pub patterns: Vec<ObfuscationPattern>,
+    /// Line numbers and contents of the lines which aren't instructions
+    pub skipped: Vec<(usize, String)>,
+}
+
+/// Operand of a normalized instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Register(String),
+    Immediate(u64),
+    /// Target of a direct branch
+    Target(u64),
+    Memory {
+        size: Option<String>,
+        /// Base and index registers (the latter possibly with a scale, e.g. `rcx*8`)
+        registers: Vec<String>,
+        displacement: i64,
+        /// RIP-relative reference to this address (from an IDA name like `off_140001000`)
+        symbol: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Instruction {
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+impl Instruction {
+    /// Parses a line in IDA or x64dbg syntax; returns `None` if it isn't an instruction
+    pub(crate) fn parse(line: &str) -> Option<Instruction> {
+        lazy_static! {
+            // `.text:0000000140001000` (IDA) or `00007FF7E4E708BC` (x64dbg)
+            static ref ADDRESS: Regex =
+                Regex::new(r"^(?:[\w.]+:)?[[:xdigit:]]{8,16}\s+").unwrap();
+            static ref MNEMONIC: Regex = Regex::new(r"^[a-z][a-z0-9]*$").unwrap();
+        }
+        let line = line.split(';').next().unwrap().trim();
+        let line = ADDRESS.replace(line, "");
+        let (mnemonic, operands) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (&line[..], ""),
+        };
+        let mnemonic = match mnemonic.to_ascii_lowercase().as_str() {
+            "retn" => "ret".to_string(),
+            "movabs" => "mov".to_string(),
+            mnemonic if MNEMONIC.is_match(mnemonic) => mnemonic.to_string(),
+            _ => return None,
+        };
+        let is_branch = BranchKind::from_mnemonic(&mnemonic).is_some();
+        let operands = if operands.is_empty() {
+            Vec::new()
+        } else {
+            operands
+                .split(',')
+                .map(|operand| parse_operand(operand, is_branch))
+                .collect::<Option<_>>()?
+        };
+        Some(Instruction { mnemonic, operands })
+    }
+}
+
+fn parse_operand(operand: &str, is_branch: bool) -> Option<Operand> {
+    lazy_static! {
+        static ref SIZE: Regex = Regex::new(r"^(byte|word|dword|qword|xmmword) ptr\s+").unwrap();
+        static ref SEGMENT: Regex = Regex::new(r"^[cdefgs]s:").unwrap();
+    }
+    let mut operand = operand.trim().to_ascii_lowercase();
+    let size = SIZE
+        .captures(&operand)
+        .map(|captures| captures[1].to_string());
+    operand = SIZE.replace(&operand, "").into_owned();
+    operand = SEGMENT.replace(&operand, "").into_owned();
+
+    if operand.starts_with('[') && operand.ends_with(']') {
+        return parse_memory(&operand[1..operand.len() - 1], size);
+    }
+    if size.is_some() {
+        return None;
+    }
+    if is_register(&operand) {
+        return Some(Operand::Register(operand));
+    }
+    if operand.starts_with("offset ") {
+        return symbol_address(operand[7..].trim()).map(Operand::Immediate);
+    }
+    if let Some(address) = symbol_address(&operand) {
+        // IDA shows RIP-relative memory operands by name only
+        return Some(if is_branch {
+            Operand::Target(address)
+        } else {
+            Operand::Memory {
+                size: None,
+                registers: Vec::new(),
+                displacement: 0,
+                symbol: Some(address),
+            }
+        });
+    }
+    let number = parse_number(&operand)?;
+    Some(if is_branch {
+        Operand::Target(number as u64)
+    } else {
+        Operand::Immediate(number as u64)
+    })
+}
+
+fn parse_memory(expression: &str, size: Option<String>) -> Option<Operand> {
+    let mut registers = Vec::new();
+    let mut displacement = 0i64;
+    let mut symbol = None;
+    // Split into signed terms
+    let expression = expression.replace(' ', "").replace('-', "+-");
+    for term in expression.split('+').filter(|term| !term.is_empty()) {
+        let (negative, term) = if term.starts_with('-') {
+            (true, &term[1..])
+        } else {
+            (false, term)
+        };
+        let sign = if negative { -1 } else { 1 };
+        let register = term.split('*').next().unwrap();
+        if is_register(register) {
+            if negative {
+                return None;
+            }
+            if register != "rip" {
+                registers.push(term.to_string());
+            }
+        } else if term.starts_with("var_") {
+            // IDA stack variables are named after their (negative) offset
+            displacement -= sign * i64::from_str_radix(&term[4..], 16).ok()?;
+        } else if term.starts_with("arg_") {
+            displacement += sign * i64::from_str_radix(&term[4..], 16).ok()?;
+        } else if let Some(address) = symbol_address(term) {
+            if negative || symbol.is_some() {
+                return None;
+            }
+            symbol = Some(address);
+        } else {
+            displacement = displacement.wrapping_add(sign * parse_number(term)?);
+        }
+    }
+    if expression.contains("rip") && symbol.is_none() && registers.is_empty() {
+        // x64dbg shows RIP-relative operands as displacements from rip
+        return Some(Operand::Memory {
+            size,
+            registers: vec!["rip".to_string()],
+            displacement,
+            symbol: None,
+        });
+    }
+    Some(Operand::Memory {
+        size,
+        registers,
+        displacement,
+        symbol,
+    })
+}
+
+fn is_register(name: &str) -> bool {
+    name == "rip" || Gpr::from_register_name(name).is_some()
+}
+
+/// Address encoded in an IDA name like `sub_140001000` or `loc_140001000`
+fn symbol_address(name: &str) -> Option<u64> {
+    lazy_static! {
+        static ref SYMBOL: Regex = Regex::new(
+            r"^(?:sub|loc|locret|off|unk|byte|word|dword|qword|xmmword|stru|asc|def|nullsub|j_sub)_([[:xdigit:]]+)$"
+        )
+        .unwrap();
+    }
+    SYMBOL
+        .captures(name)
+        .and_then(|captures| u64::from_str_radix(&captures[1], 16).ok())
+}
+
+/// Parses a number in IDA (`10h`, `0FFh`) or C syntax
+fn parse_number(number: &str) -> Option<i64> {
+    let (negative, number) = if number.starts_with('-') {
+        (true, &number[1..])
+    } else {
+        (false, number)
+    };
+    let value = if number.starts_with("0x") {
+        u64::from_str_radix(&number[2..], 16).ok()?
+    } else if number.ends_with('h') {
+        u64::from_str_radix(&number[..number.len() - 1], 16).ok()?
+    } else {
+        number.parse().ok()?
+    } as i64;
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+impl Instruction {
+    /// Whether an operand is relative to the instruction's address
+    pub(crate) fn is_rip_relative(&self) -> bool {
+        self.operands.iter().any(|operand| match operand {
+            Operand::Memory {
+                registers, symbol, ..
+            } => symbol.is_some() || registers.iter().any(|register| register == "rip"),
+            _ => false,
+        })
+    }
+}
+
+/// Replaces concrete registers, addresses and branch targets with variables which are shared
+/// between a pattern and its replacement. RIP-relative displacements depend on the address of
+/// their instruction so each one gets a variable of its own. An instruction gets at most one `$num`
+/// variable (which is all the matcher supports); further numbers stay concrete.
+#[derive(Default)]
+pub(crate) struct Generalizer {
+    registers: FxHashMap<String, String>,
+    numbers: FxHashMap<u64, String>,
+    labels: FxHashMap<u64, String>,
+    /// Number of `$num` variables which have been introduced
+    number_variables: usize,
+    /// Whether the instruction which is being generalized already has a `$num` variable
+    has_number: bool,
+}
+
+impl Generalizer {
+    pub(crate) fn generalize(&mut self, instruction: &Instruction) -> String {
+        // Memory operands come first as a RIP-relative displacement has to be a variable
+        self.has_number = false;
+        let mut operands = vec![String::new(); instruction.operands.len()];
+        let (memory, others): (Vec<_>, Vec<_>) =
+            instruction
+                .operands
+                .iter()
+                .enumerate()
+                .partition(|(_, operand)| match operand {
+                    Operand::Memory { .. } => true,
+                    _ => false,
+                });
+        for (i, operand) in memory.into_iter().chain(others) {
+            operands[i] = self.operand(operand);
+        }
+        if operands.is_empty() {
+            instruction.mnemonic.clone()
+        } else {
+            format!("{} {}", instruction.mnemonic, operands.join(", "))
+        }
+    }
+
+    fn operand(&mut self, operand: &Operand) -> String {
+        match operand {
+            Operand::Register(register) => self.register(register),
+            Operand::Immediate(value) => self.number(*value),
+            Operand::Target(target) => {
+                let n = self.labels.len() + 1;
+                self.labels
+                    .entry(*target)
+                    .or_insert_with(|| format!("$label:l{}", n))
+                    .clone()
+            }
+            Operand::Memory {
+                size,
+                registers,
+                displacement,
+                symbol,
+            } => {
+                let mut text = String::new();
+                if let Some(size) = size {
+                    write!(text, "{} ptr ", size).unwrap();
+                }
+                let mut terms: Vec<_> = registers
+                    .iter()
+                    .map(|register| match register.find('*') {
+                        Some(index) => {
+                            format!(
+                                "{}{}",
+                                self.register(&register[..index]),
+                                &register[index..]
+                            )
+                        }
+                        None => self.register(register),
+                    })
+                    .collect();
+                let rip_relative = symbol.is_some() || registers.iter().any(|r| r == "rip");
+                if rip_relative {
+                    terms.retain(|term| term != "rip");
+                    terms.insert(0, "rip".to_string());
+                }
+                let magnitude = displacement.wrapping_abs() as u64;
+                let generalized =
+                    !rip_relative && magnitude >= MIN_GENERALIZED_NUMBER && !self.has_number;
+                if rip_relative {
+                    terms.push(self.fresh_variable());
+                } else if generalized {
+                    terms.push(self.variable(*displacement as u64));
+                } else if terms.is_empty() || *displacement > 0 {
+                    terms.push(format!("0x{:x}", displacement));
+                }
+                write!(text, "[{}", terms.join(" + ")).unwrap();
+                if *displacement < 0 && !rip_relative && !generalized {
+                    write!(text, " - 0x{:x}", magnitude).unwrap();
+                }
+                text.push(']');
+                text
+            }
+        }
+    }
+
+    /// Registers which can be expressed by `$reg` become variables except for the stack pointer
+    fn register(&mut self, register: &str) -> String {
+        let generalizable = Register::all()
+            .iter()
+            .any(|variable_register| variable_register.name().eq_ignore_ascii_case(register))
+            && Gpr::from_register_name(register) != Some(Gpr::Rsp);
+        if !generalizable {
+            return register.to_string();
+        }
+        let n = self.registers.len() + 1;
+        self.registers
+            .entry(register.to_string())
+            .or_insert_with(|| format!("$reg:r{}", n))
+            .clone()
+    }
+
+    fn number(&mut self, value: u64) -> String {
+        let magnitude = (value as i64).wrapping_abs() as u64;
+        if magnitude < MIN_GENERALIZED_NUMBER || self.has_number {
+            format!("0x{:x}", value)
+        } else {
+            self.variable(value)
+        }
+    }
+
+    /// Variable which is shared by all occurrences of `value`
+    fn variable(&mut self, value: u64) -> String {
+        self.has_number = true;
+        let number_variables = &mut self.number_variables;
+        self.numbers
+            .entry(value)
+            .or_insert_with(|| {
+                *number_variables += 1;
+                format!("$num:n{}", number_variables)
+            })
+            .clone()
+    }
+
+    /// Variable which isn't shared with any other operand
+    fn fresh_variable(&mut self) -> String {
+        self.has_number = true;
+        self.number_variables += 1;
+        format!("$num:n{}", self.number_variables)
+    }
+}
+
+/// Snippet of a listing
+#[derive(Default)]
+struct Snippet {
+    line: usize,
+    pattern: Vec<Instruction>,
+    replacement: Option<Vec<Instruction>>,
+}
+
+/// Imports the snippets of a listing as disabled draft patterns tagged `draft`. `source` is
+/// recorded in their metadata.
+pub fn import(listing: &str, source: &str) -> ImportReport {
+    lazy_static! {
+        static ref LABEL: Regex = Regex::new(r"^[\w.]+:$").unwrap();
+    }
+    let mut snippets = Vec::new();
+    let mut snippet = Snippet::default();
+    let mut skipped = Vec::new();
+    for (i, raw_line) in listing.lines().enumerate() {
+        let line = raw_line.trim();
+        if line.is_empty() || line == "{" || line == "}" {
+            if !snippet.pattern.is_empty() {
+                snippets.push(snippet);
+            }
+            snippet = Snippet::default();
+            continue;
+        }
+        if LABEL.is_match(line) {
+            continue;
+        }
+        let (text, replacement) = if line.starts_with('=') {
+            // The replacement may be separated from its snippet by a blank line
+            if snippet.pattern.is_empty() {
+                if let Some(previous) = snippets.pop() {
+                    snippet = previous;
+                }
+            }
+            snippet.replacement = Some(Vec::new());
+            (line[1..].trim(), true)
+        } else {
+            (line, snippet.replacement.is_some())
+        };
+        if text.is_empty() {
+            continue;
+        }
+        match Instruction::parse(text) {
+            Some(instruction) if replacement => {
+                snippet.replacement.as_mut().unwrap().push(instruction)
+            }
+            Some(instruction) => {
+                if snippet.pattern.is_empty() {
+                    snippet.line = i + 1;
+                }
+                snippet.pattern.push(instruction);
+            }
+            None => skipped.push((i + 1, line.to_string())),
+        }
+    }
+    if !snippet.pattern.is_empty() {
+        snippets.push(snippet);
+    }
+
+    let patterns = snippets
+        .into_iter()
+        .filter_map(|snippet| draft(&snippet, source))
+        .collect();
+    ImportReport { patterns, skipped }
+}
+
+fn draft(snippet: &Snippet, source: &str) -> Option<ObfuscationPattern> {
+    let mut generalizer = Generalizer::default();
+    let pattern = snippet
+        .pattern
+        .iter()
+        .map(|instruction| generalizer.generalize(instruction).parse())
+        .collect::<Result<Vec<PatternElement>, _>>()
+        .ok()?;
+    let replacement = snippet
+        .replacement
+        .iter()
+        .flatten()
+        .map(|instruction| generalizer.generalize(instruction).parse())
+        .collect::<Result<Vec<InstructionPattern>, _>>()
+        .ok()?;
+
+    let description = match &snippet.replacement {
+        None => Some("The listing has no replacement".to_string()),
+        Some(replacement) if replacement.iter().any(Instruction::is_rip_relative) => Some(
+            "The RIP-relative displacements of the replacement have to be adjusted to the ones of \
+             the pattern"
+                .to_string(),
+        ),
+        Some(_) => None,
+    };
+    let mut pattern = ObfuscationPattern::new(pattern, replacement);
+    pattern.set_metadata(PatternMetadata {
+        name: Some(format!("Imported from line {}", snippet.line)),
+        description,
+        source: Some(source.to_string()),
+        tags: vec!["draft".to_string()],
+        ..PatternMetadata::default()
+    });
+    pattern.set_enabled(false);
+    Some(pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_ida_listing() {
+        let listing = "\
+loc_140B02DD3:
+mov     [rsp+8+var_10], rbp
+lea     rsp, [rsp-8]
+lea     rbp, sub_140C5A59D
+xchg    rbp, [rsp+10h+var_10]
+
+= lea [rsp+8+var_10], sub_140C5A59D
+  sub rsp, 8
+
+elias paper patterns
+";
+        let report = import(listing, "patterns.txt");
+        assert_eq!(
+            report.skipped,
+            vec![(10, "elias paper patterns".to_string())]
+        );
+        assert_eq!(report.patterns.len(), 1);
+        let pattern = &report.patterns[0];
+        assert_eq!(
+            pattern.texts().0,
+            vec![
+                "mov [rsp - 0x8], $reg:r1",
+                "lea rsp, [rsp - 0x8]",
+                "lea $reg:r1, [rip + $num:n1]",
+                "xchg $reg:r1, [rsp]",
+            ]
+        );
+        // The replacement is generalized with the same variables but the RIP-relative displacement
+        // differs at another position
+        assert_eq!(
+            pattern.texts().1,
+            vec!["lea [rsp - 0x8], [rip + $num:n2]", "sub rsp, 0x8"]
+        );
+        assert!(pattern
+            .metadata()
+            .description
+            .as_ref()
+            .unwrap()
+            .contains("RIP-relative"));
+        assert!(!pattern.is_enabled());
+        assert_eq!(pattern.metadata().source, Some("patterns.txt".to_string()));
+    }
+
+    #[test]
+    fn import_x64dbg_listing() {
+        let listing = "\
+{
+   00007FF7E4E708F3     push rbp
+   00007FF7E4E708F4     movabs rbp, 0x7ff7e4e31702
+   00007FF7E4E708FE     xchg qword ptr [rsp], rbp
+   00007FF7E4E7091E     ret
+}
+   00007FF7E43A3565     mov eax, dword ptr [rip + 0x10c19e]
+   00007FF7E43A356B     jmp 0x7ff7e4ffea4a
+
+   00007FF7E43A3570     mov qword ptr [rip + 0x10c19e], 0x12345
+   00007FF7E43A357B     mov rax, 0x12345
+";
+        let report = import(listing, "x64dbg");
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.patterns.len(), 3);
+        assert_eq!(
+            report.patterns[0].texts().0,
+            vec![
+                "push $reg:r1",
+                "mov $reg:r1, $num:n1",
+                "xchg qword ptr [rsp], $reg:r1",
+                "ret",
+            ]
+        );
+        assert_eq!(
+            report.patterns[1].texts().0,
+            vec!["mov $reg:r1, dword ptr [rip + $num:n1]", "jmp $label:l1"]
+        );
+        // Only one number per instruction becomes a variable
+        assert_eq!(
+            report.patterns[2].texts().0,
+            vec![
+                "mov qword ptr [rip + $num:n1], 0x12345",
+                "mov $reg:r1, $num:n2"
+            ]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use fxhash::FxHashSet;
+
+use crate::binary::{code_at, Span};
+use crate::disassembly::Disassembler;
+use crate::x86::Location;
+
+/// Maximum number of instructions which are inspected on all paths together
+const MAX_INSTRUCTIONS: usize = 64;
+
+/// Whether none of `locations` is read before being overwritten when execution continues at
+/// `address`. Direct jumps and both sides of conditional branches are followed. Calls are assumed
+/// to read all registers but no flags (which calling conventions don't preserve); returns,
+/// indirect branches and code which can't be decoded are assumed to read everything. The analysis
+/// gives up (returning `false`) after `MAX_INSTRUCTIONS`.
+pub fn is_dead(
+    spans: &[Span],
+    address: u64,
+    locations: &FxHashSet<Location>,
+    disassembler: &Disassembler,
+) -> bool {
+    let mut budget = MAX_INSTRUCTIONS;
+    let mut visited = FxHashSet::default();
+    is_dead_from(
+        spans,
+        address,
+        locations.clone(),
+        disassembler,
+        &mut budget,
+        &mut visited,
+    )
+}
+
+fn is_dead_from(
+    spans: &[Span],
+    mut address: u64,
+    mut live: FxHashSet<Location>,
+    disassembler: &Disassembler,
+    budget: &mut usize,
+    visited: &mut FxHashSet<(u64, Vec<Location>)>,
+) -> bool {
+    loop {
+        if live.is_empty() {
+            return true;
+        }
+        // A path which loops back without reading any of the locations doesn't make them live
+        let mut key: Vec<_> = live.iter().cloned().collect();
+        key.sort();
+        if !visited.insert((address, key)) {
+            return true;
+        }
+        if *budget == 0 {
+            return false;
+        }
+        *budget -= 1;
+
+        let instruction =
+            match code_at(spans, address).and_then(|code| disassembler.decode(code, address)) {
+                Some(instruction) => instruction,
+                None => return false,
+            };
+        if instruction
+            .reads()
+            .iter()
+            .any(|location| live.contains(location))
+        {
+            return false;
+        }
+        for location in instruction.writes() {
+            live.remove(&location);
+        }
+
+        if !instruction.is_control_transfer() {
+            address = instruction.end();
+            continue;
+        }
+        match (instruction.mnemonic(), instruction.branch_target()) {
+            ("call", _) => {
+                return live.iter().all(|location| match location {
+                    Location::Flag(_) => true,
+                    Location::Register(_) => false,
+                });
+            }
+            ("jmp", Some(target)) => address = target,
+            (mnemonic, Some(target)) if mnemonic.starts_with('j') => {
+                if !is_dead_from(spans, target, live.clone(), disassembler, budget, visited) {
+                    return false;
+                }
+                address = instruction.end();
+            }
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::{Flag, Gpr};
+
+    #[test]
+    fn dead_locations() {
+        let spans = [Span::test(vec![
+            0x48, 0x89, 0xD8, // mov rax, rbx
+            0x48, 0x85, 0xC9, // test rcx, rcx
+            0x74, 0x01, // je 0x1009
+            0xC3, // ret
+            0x50, // push rax
+            0x31, 0xC0, // xor eax, eax
+            0xC3, // ret
+        ])];
+        let disassembler = Disassembler::new();
+        let flags = Location::flags().collect();
+        let rax = [Location::Register(Gpr::Rax)].iter().cloned().collect();
+        let rbx = [Location::Register(Gpr::Rbx)].iter().cloned().collect();
+        let zf = [Location::Flag(Flag::Zf)].iter().cloned().collect();
+
+        // test overwrites all flags before je reads them
+        assert!(is_dead(&spans, 0x1000, &flags, &disassembler));
+        // mov overwrites rax
+        assert!(is_dead(&spans, 0x1000, &rax, &disassembler));
+        // mov reads rbx
+        assert!(!is_dead(&spans, 0x1000, &rbx, &disassembler));
+        // je reads zf
+        assert!(!is_dead(&spans, 0x1006, &zf, &disassembler));
+        // push reads rax on the taken path
+        assert!(!is_dead(&spans, 0x1003, &rax, &disassembler));
+    }
+}
@@ -3,17 +3,37 @@
 #[macro_use]
 extern crate log;
 
+use std::collections::BTreeSet;
 use std::fs;
-use std::ops::Range;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
 
-use goblin::pe::PE;
+use fxhash::FxHashMap;
 use goblin::Object;
 use number_prefix::NumberPrefix;
+use structopt::clap;
 use structopt::StructOpt;
 
-use pattern_based_deobfuscator::nasm_assemble;
+use pattern_based_deobfuscator::binary::{
+    function_starts, get_code_segments, relocation_targets, Span,
+};
+use pattern_based_deobfuscator::cfg::ControlFlowGraph;
+use pattern_based_deobfuscator::code_cave::CodeCaves;
+use pattern_based_deobfuscator::compaction::{self, CompactionReport};
+use pattern_based_deobfuscator::conflict::{self, Candidate, ConflictPolicy};
+use pattern_based_deobfuscator::disassembly::{DecodedInstruction, Disassembler};
+use pattern_based_deobfuscator::generalize::{generalize, Example};
+use pattern_based_deobfuscator::jump_chain;
+use pattern_based_deobfuscator::lint;
+use pattern_based_deobfuscator::listing;
+use pattern_based_deobfuscator::mining;
 use pattern_based_deobfuscator::pattern::*;
+use pattern_based_deobfuscator::pattern_database::{
+    PatternDatabase, PatternFilter, PatternSelector,
+};
+use pattern_based_deobfuscator::pattern_dsl;
+use pattern_based_deobfuscator::rewrite::{ReplacementOutcome, ReplacementStatistics, Rewriter};
+use pattern_based_deobfuscator::verify::{self, Verdict};
 
 #[derive(Debug, StructOpt)]
 struct Opt {
@@ -23,53 +43,383 @@ struct Opt {
     /// Disable output of deobfuscated binary
     #[structopt(short = "n", long = "no-output")]
     no_output: bool,
-    /// The pattern database to use
+    /// The pattern database to use; multiple databases are layered in the given order so later
+    /// patterns replace earlier ones with the same id
     #[structopt(
         short = "d",
         long = "database",
         parse(from_os_str),
+        raw(number_of_values = "1"),
         default_value = "pattern_database.json"
     )]
-    pattern_database: PathBuf,
+    pattern_databases: Vec<PathBuf>,
+    /// Only use the patterns selected by `tag:<tag>`, an index (starting at 1), an id or a name;
+    /// disabled patterns can be included by their index, id or name
+    #[structopt(long = "include", raw(number_of_values = "1"))]
+    include: Vec<PatternSelector>,
+    /// Don't use the patterns selected by `tag:<tag>`, an index, an id or a name
+    #[structopt(long = "exclude", raw(number_of_values = "1"))]
+    exclude: Vec<PatternSelector>,
+    /// Byte sequence (in hex, e.g. `0f1f00`) which is treated as filler between pattern
+    /// instructions and used to pad replacements; replaces the default set of NOP encodings and
+    /// has to include a single-byte filler
+    #[structopt(
+        long = "filler",
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_hex_bytes")
+    )]
+    fillers: Vec<Vec<u8>>,
+    /// Place replacements which are larger than the replaced code into code caves (int3 padding
+    /// and unused space at the end of code sections) instead of skipping them
+    #[structopt(long = "code-caves")]
+    code_caves: bool,
+    /// Move the filler inside of the basic blocks of the recovered control-flow graph to their end
+    /// after all replacements so the instructions are contiguous (requires --cfg)
+    #[structopt(long = "compact", raw(requires = r#""cfg""#))]
+    compact: bool,
+    /// Retarget branches to chains of unconditional jumps (and equivalent push/ret trampolines) to
+    /// the final destination after all replacements (requires --cfg)
+    #[structopt(long = "jump-chains", raw(requires = r#""cfg""#))]
+    jump_chains: bool,
+    /// Recover the control-flow graph (from the entry point, exports and exception data) before
+    /// each pass and don't match across basic block boundaries
+    #[structopt(long = "cfg")]
+    cfg: bool,
+    /// Only match at the start of decoded instructions (from a linear sweep or, with --cfg, the
+    /// recovered control-flow graph)
+    #[structopt(long = "instruction-boundaries")]
+    instruction_boundaries: bool,
+    /// Match all patterns in a single scan and resolve overlapping matches by the given policy:
+    /// `longest` (the longest match wins), `priority` (the pattern with the highest priority wins)
+    /// or `order` (the earlier pattern in the database wins)
+    #[structopt(long = "conflicts")]
+    conflicts: Option<ConflictPolicy>,
+    /// Stop after this many passes (at least 1) even if the last pass still replaced something
+    #[structopt(
+        long = "max-passes",
+        default_value = "100",
+        parse(try_from_str = "parse_max_passes")
+    )]
+    max_passes: usize,
     /// Deobfucated output binary; defaults to <input>.deobf.exe
     #[structopt(parse(from_os_str), short = "o", long = "output")]
     output: Option<PathBuf>,
-    /// Obfuscated input
+    /// Obfuscated input; required unless a subcommand is given
     #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    input: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Check that the replacement of each pattern in the database has the same effects as the
+    /// pattern by executing both symbolically
+    #[structopt(name = "verify")]
+    Verify {
+        /// Only verify these patterns (in addition to --include)
+        patterns: Vec<PatternSelector>,
+    },
+    /// Check the pattern database for patterns which never match, duplicates, patterns which are
+    /// subsumed or shadowed by other ones and replacements which rewrite into each other
+    #[structopt(name = "lint")]
+    Lint,
+    /// Print the (layered) pattern database in the text format
+    #[structopt(name = "format")]
+    Format,
+    /// Print draft patterns (in the text format) for the snippets of an IDA or x64dbg listing
+    #[structopt(name = "import")]
+    Import {
+        #[structopt(parse(from_os_str))]
+        listing: PathBuf,
+    },
+    /// Print the most specific pattern which covers all example pairs; the examples are given in
+    /// the text format with concrete instructions
+    #[structopt(name = "generalize")]
+    Generalize {
+        #[structopt(parse(from_os_str))]
+        examples: PathBuf,
+    },
+    /// Print the most frequent instruction sequences (with registers, addresses and branch targets
+    /// generalized into variables) of a binary's code sections as draft patterns
+    #[structopt(name = "mine")]
+    Mine {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        /// Minimum number of instructions in a sequence
+        #[structopt(long = "min-length", default_value = "2")]
+        min_length: usize,
+        /// Maximum number of instructions in a sequence
+        #[structopt(long = "max-length", default_value = "4")]
+        max_length: usize,
+        /// Number of sequences to print
+        #[structopt(long = "top", default_value = "20")]
+        top: usize,
+    },
+}
+
+fn parse_hex_bytes(hex: &str) -> Result<Vec<u8>, String> {
+    let hex: String = hex.split_whitespace().collect();
+    // Checking the digits first keeps the slices below on character boundaries
+    if hex.is_empty() || hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid byte sequence: {}", hex));
+    }
+    Ok((0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+fn parse_max_passes(passes: &str) -> Result<usize, String> {
+    match passes.parse() {
+        Ok(0) => Err("at least one pass is required".to_string()),
+        Ok(passes) => Ok(passes),
+        Err(error) => Err(format!("invalid number of passes: {}", error)),
+    }
 }
 
 fn main() {
     env_logger::init();
 
-    let mut opt = Opt::from_args();
-    if opt.output.is_none() {
-        let mut new_file_name = opt.input.file_stem().unwrap().to_owned();
-        new_file_name.push(".deobf");
-        if let Some(ext) = opt.input.extension() {
-            new_file_name.push(".");
-            new_file_name.push(ext);
+    let opt = Opt::from_args();
+
+    let filler_set = if opt.fillers.is_empty() {
+        FillerSet::default()
+    } else {
+        match FillerSet::new(opt.fillers.clone()) {
+            Ok(filler_set) => filler_set,
+            Err(error) => {
+                eprintln!("Invalid --filler: {}", error);
+                process::exit(1);
+            }
+        }
+    };
+
+    match opt.command {
+        Some(Command::Verify { ref patterns }) => verify_patterns(&opt, patterns),
+        Some(Command::Lint) => lint_database(&opt, &filler_set),
+        Some(Command::Format) => {
+            print!("{}", pattern_dsl::format(&load_database(&opt)));
         }
-        opt.output = Some(opt.input.with_file_name(new_file_name));
+        Some(Command::Import { ref listing }) => import_listing(listing),
+        Some(Command::Generalize { ref examples }) => generalize_examples(examples),
+        Some(Command::Mine {
+            ref input,
+            min_length,
+            max_length,
+            top,
+        }) => mine_sequences(input, min_length, max_length, top, &filler_set),
+        None => deobfuscate(&opt, &filler_set),
     }
+}
 
-    let pattern_database =
-        pattern_based_deobfuscator::load_pattern_database_from_json(opt.pattern_database)
-            .expect("failed to parse pattern database");
+/// Code sections and addresses of an input binary
+struct Binary {
+    buffer: Vec<u8>,
+    spans: Vec<Span>,
+    function_starts: Vec<u64>,
+    relocation_targets: Vec<u64>,
+}
 
-    let buffer = fs::read(&opt.input).unwrap();
-    let mut deobfuscated_binary = buffer.clone();
-    let mut spans = match Object::parse(&buffer).unwrap() {
-        Object::PE(pe) => get_code_segments(pe, &buffer),
-        Object::Elf(_) | Object::Mach(_) | Object::Archive(_) => {
-            unimplemented!("Only PE files are supported atm!");
+fn load_binary(path: &Path) -> Binary {
+    let buffer = match fs::read(path) {
+        Ok(buffer) => buffer,
+        Err(error) => {
+            eprintln!("Failed to read {}: {}", path.display(), error);
+            process::exit(1);
+        }
+    };
+    let (spans, function_starts, relocation_targets) = match Object::parse(&buffer) {
+        Ok(Object::PE(pe)) => (
+            get_code_segments(&pe, &buffer),
+            function_starts(&pe, &buffer),
+            relocation_targets(&pe, &buffer),
+        ),
+        Ok(_) => {
+            eprintln!("{}: Only PE files are supported atm!", path.display());
+            process::exit(1);
+        }
+        Err(error) => {
+            eprintln!("Failed to parse {}: {}", path.display(), error);
+            process::exit(1);
         }
-        Object::Unknown(magic) => panic!("unknown magic: {:#x}", magic),
     };
+    Binary {
+        buffer,
+        spans,
+        function_starts,
+        relocation_targets,
+    }
+}
+
+fn read_text(path: &Path) -> String {
+    match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) => {
+            eprintln!("Failed to read {}: {}", path.display(), error);
+            process::exit(1);
+        }
+    }
+}
+
+fn load_database(opt: &Opt) -> PatternDatabase {
+    match PatternDatabase::load_all(&opt.pattern_databases) {
+        Ok(pattern_database) => pattern_database,
+        Err(error) => {
+            eprintln!("Failed to load the pattern database: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn pattern_filter(opt: &Opt) -> PatternFilter {
+    PatternFilter {
+        include: opt.include.clone(),
+        exclude: opt.exclude.clone(),
+    }
+}
 
+/// Linear sweep over the mapped code of all spans
+fn linear_sweep(spans: &[Span], disassembler: &Disassembler) -> Vec<DecodedInstruction> {
+    spans
+        .iter()
+        .flat_map(|span| {
+            let mapped = span.virtual_size.min(span.code.len());
+            disassembler.linear_sweep(&span.code[..mapped], span.vaddr as u64)
+        })
+        .collect()
+}
+
+fn verify_patterns(opt: &Opt, patterns: &[PatternSelector]) {
+    let pattern_database = load_database(opt);
+    let mut pattern_filter = pattern_filter(opt);
+    pattern_filter.include.extend(patterns.iter().cloned());
+    let differing = verify_database(&pattern_database, &pattern_filter);
+    process::exit(if differing == 0 { 0 } else { 1 });
+}
+
+fn lint_database(opt: &Opt, filler_set: &FillerSet) {
+    let pattern_database = load_database(opt);
+    let findings = lint::lint(&pattern_database, &pattern_filter(opt), filler_set);
+    for finding in &findings {
+        println!("{}", finding.describe(&pattern_database));
+    }
+    println!("{} findings", findings.len());
+    process::exit(if findings.is_empty() { 0 } else { 1 });
+}
+
+fn import_listing(listing: &Path) {
+    let report = listing::import(&read_text(listing), &listing.display().to_string());
+    for (line, content) in &report.skipped {
+        eprintln!("Skipped line {}: {}", line, content);
+    }
+    print!(
+        "{}",
+        pattern_dsl::format(&PatternDatabase::new(report.patterns))
+    );
+}
+
+fn generalize_examples(path: &Path) {
+    let examples = match pattern_dsl::parse(&read_text(path)) {
+        Ok(examples) => examples,
+        Err(error) => {
+            eprintln!("{}: {}", path.display(), error);
+            process::exit(1);
+        }
+    };
+    let examples: Vec<_> = examples
+        .patterns()
+        .iter()
+        .map(Example::from_pattern)
+        .collect();
+    match generalize(&examples) {
+        Ok(pattern) => print!(
+            "{}",
+            pattern_dsl::format(&PatternDatabase::new(vec![pattern]))
+        ),
+        Err(error) => {
+            eprintln!("Generalization failed: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn mine_sequences(
+    input: &Path,
+    min_length: usize,
+    max_length: usize,
+    top: usize,
+    filler_set: &FillerSet,
+) {
+    if min_length == 0 || min_length > max_length {
+        eprintln!("Invalid sequence lengths: {}..{}", min_length, max_length);
+        process::exit(1);
+    }
+    let binary = load_binary(input);
+    let swept = linear_sweep(&binary.spans, &Disassembler::new());
+    let sequences = mining::mine(&swept, min_length..=max_length, filler_set);
+    eprintln!(
+        "Found {} repeated sequences in {} instructions",
+        sequences.len(),
+        swept.len()
+    );
+    let drafts = sequences
+        .iter()
+        .take(top)
+        .enumerate()
+        .filter_map(|(i, sequence)| sequence.draft(i + 1))
+        .collect();
+    print!("{}", pattern_dsl::format(&PatternDatabase::new(drafts)));
+}
+
+fn deobfuscate(opt: &Opt, filler_set: &FillerSet) {
+    let pattern_database = load_database(opt);
+    let pattern_filter = pattern_filter(opt);
+
+    let input = match opt.input {
+        Some(ref input) => input,
+        None => clap::Error::with_description(
+            "the input file is required",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
+    let output = match opt.output {
+        Some(ref output) => output.clone(),
+        None => {
+            let mut new_file_name = input.file_stem().unwrap().to_owned();
+            new_file_name.push(".deobf");
+            if let Some(ext) = input.extension() {
+                new_file_name.push(".");
+                new_file_name.push(ext);
+            }
+            input.with_file_name(new_file_name)
+        }
+    };
+
+    let Binary {
+        buffer: mut deobfuscated_binary,
+        mut spans,
+        function_starts,
+        relocation_targets,
+    } = load_binary(input);
+
+    // The matchers are built once; invalid patterns are reported and skipped
+    let mut selected = Vec::new();
+    for (pattern_n, pattern) in pattern_database.select(&pattern_filter) {
+        match ObfuscationPatternMatcher::for_pattern(pattern, filler_set.clone()) {
+            Ok(matcher) => selected.push((pattern_n, pattern, matcher)),
+            Err(error) => eprintln!("Skipping pattern {}: {}", pattern.label(pattern_n), error),
+        }
+    }
+    if selected.is_empty() {
+        eprintln!("No patterns are selected");
+        process::exit(1);
+    }
     println!(
-        "Deobfuscating {} using a database of {} patterns...",
-        opt.input.display(),
+        "Deobfuscating {} using {} of {} patterns in the database...",
+        input.display(),
+        selected.len(),
         pattern_database.patterns().len()
     );
 
@@ -81,127 +431,254 @@ fn main() {
     };
 
     println!("Combined length of code sections: {}", code_size);
+
+    let code_caves = if opt.code_caves {
+        let code_caves = CodeCaves::find(&spans);
+        println!("Free space in code caves: {} bytes", code_caves.free());
+        Some(code_caves)
+    } else {
+        None
+    };
+    let disassembler = Disassembler::new();
+    let mut rewriter = Rewriter::new(filler_set.clone(), code_caves);
+    let mut trampolines = Vec::new();
+
     let mut found_total_total = 0;
-    let mut replaced_total_total = 0;
-    let mut found_total;
-    let mut replaced_total = 1;
+    let mut statistics_total_total = ReplacementStatistics::default();
     let mut pass_n = 0;
-
-    while replaced_total > 0 {
-        found_total = 0;
-        replaced_total = 0;
+    // Number of passes in which each pattern was applied
+    let mut applied_passes: FxHashMap<usize, usize> = FxHashMap::default();
+    // Code before each pass by its hash
+    let mut earlier_states = FxHashMap::default();
+    insert_code_state(&mut earlier_states, &spans);
+
+    loop {
+        let mut found_total = 0;
+        let mut statistics_total = ReplacementStatistics::default();
         pass_n += 1;
         println!("================== Pass {} ==================", pass_n);
-
-        for (pattern_n, pattern) in pattern_database
-            .patterns()
+        // Patterns which were applied in this pass with the number of applied replacements
+        let mut applied_patterns = Vec::new();
+
+        let cfg = if opt.cfg {
+            let cfg = ControlFlowGraph::recover(&spans, &function_starts, &disassembler);
+            println!(
+                "Recovered {} basic blocks with {} instructions",
+                cfg.blocks().count(),
+                cfg.instructions().len()
+            );
+            Some(cfg)
+        } else {
+            None
+        };
+
+        // Code which may be reached from elsewhere must not be in the middle of a replaced match
+        let mut branch_targets: BTreeSet<u64> = function_starts
             .iter()
-            .enumerate()
-            .map(|(i, x)| (i + 1, x))
-        {
-            println!("Searching for pattern {}...", pattern_n);
-            let mut found = 0;
-            let mut replaced = 0;
-
-            let instruction_patterns = pattern.instruction_patterns().to_vec();
-            let obfuscation_pattern_matcher =
-                ObfuscationPatternMatcher::new(instruction_patterns).unwrap();
-            for span in &mut spans {
-                for (instantiated_variables, start, end) in
-                    &obfuscation_pattern_matcher.match_against(&span.code)
-                {
-                    found += 1;
-
-                    if opt.verbosity >= 2 {
-                        println!(
-                            "Found pattern {} ({}): 0x{:x} - 0x{:x}",
-                            pattern_n,
-                            found,
-                            start + span.vaddr,
-                            end + span.vaddr
-                        );
-                    }
-
-                    if opt.no_output {
-                        continue;
-                    }
-
-                    let mut replacement_asm = pattern
-                        .replacement()
-                        .iter()
-                        .map(InstructionPattern::pattern)
-                        .collect::<Vec<_>>()
-                        .join("\n");
-
-                    trace!("Variable instantiations: {:?}", instantiated_variables);
+            .chain(&relocation_targets)
+            .cloned()
+            .collect();
+        let swept = linear_sweep(&spans, &disassembler);
+        branch_targets.extend(compaction::referenced_addresses(&swept));
+        if let Some(ref cfg) = cfg {
+            branch_targets.extend(cfg.leaders());
+        }
+        rewriter.set_branch_targets(branch_targets);
+
+        let instruction_starts: Option<BTreeSet<u64>> = if opt.instruction_boundaries {
+            Some(match cfg {
+                Some(ref cfg) => cfg.instructions().keys().cloned().collect(),
+                None => swept
+                    .iter()
+                    .map(|instruction| instruction.address())
+                    .collect(),
+            })
+        } else {
+            None
+        };
+        let constraints = MatchConstraints {
+            leaders: cfg.as_ref().map(ControlFlowGraph::leaders),
+            instruction_starts: instruction_starts.as_ref(),
+        };
+        let mut discarded_total = 0;
+
+        // Without a conflict policy each pattern is matched against the code as rewritten by the
+        // previous patterns; with one all patterns are matched in a single scan
+        let scans: Vec<Vec<usize>> = match opt.conflicts {
+            Some(_) => vec![(0..selected.len()).collect()],
+            None => (0..selected.len()).map(|i| vec![i]).collect(),
+        };
+        for scan in scans {
+            let mut found = vec![0; selected.len()];
+            let mut statistics = vec![ReplacementStatistics::default(); selected.len()];
+
+            let mut candidates = Vec::new();
+            for &i in &scan {
+                let (pattern_n, pattern, ref matcher) = selected[i];
+                let (pattern_candidates, discarded) =
+                    find_candidates(opt, &spans, i, pattern_n, pattern, matcher, &constraints);
+                found[i] = pattern_candidates.len();
+                discarded_total += discarded;
+                candidates.extend(pattern_candidates);
+            }
 
-                    for instantiated_variable in instantiated_variables {
-                        let variable = instantiated_variable.as_variable();
-                        let value = instantiated_variable.value();
-                        replacement_asm = replacement_asm.replace(&variable.to_string(), &value);
+            // Returns whether the candidate was replaced
+            let mut apply = |candidate: &Candidate| {
+                if opt.no_output {
+                    // Only the conflicts are resolved
+                    return true;
+                }
+                let (pattern_n, pattern, _) = selected[candidate.pattern];
+                let (span_index, pattern_match) = (candidate.span_index, &candidate.pattern_match);
+
+                trace!("Variable instantiations: {:?}", pattern_match.variables());
+
+                let outcome = rewriter.apply(&mut spans, span_index, pattern, pattern_match);
+                if opt.verbosity >= 2 {
+                    println!(
+                        "Pattern {} at 0x{:x}: {}",
+                        pattern.label(pattern_n),
+                        pattern_match.start() + spans[span_index].vaddr,
+                        outcome
+                    );
+                }
+                if !outcome.is_applied() {
+                    warn!(
+                        "Replacement of pattern {} at 0x{:x}: {}",
+                        pattern.label(pattern_n),
+                        pattern_match.start() + spans[span_index].vaddr,
+                        outcome
+                    );
+                }
+                statistics[candidate.pattern].record(&outcome);
+                let applied = outcome.is_applied();
+                if let ReplacementOutcome::AppliedInCodeCave(trampoline) = outcome {
+                    trampolines.push(trampoline);
+                }
+                applied
+            };
+
+            match opt.conflicts {
+                Some(policy) => {
+                    let resolution = conflict::resolve(candidates, policy, &mut apply);
+                    for candidate in &resolution.dropped {
+                        statistics[candidate.pattern].record(&ReplacementOutcome::Conflicting);
                     }
-
-                    // let offset = span.range_in_file.start;
-                    match nasm_assemble(&replacement_asm, *start as u64 + span.vaddr as u64) {
-                        Ok(mut asm) => {
-                            if asm.len() > end - start {
-                                warn!(
-                                    "Can't replace pattern as replacement is larger than original!"
-                                );
-                            }
-                            asm.resize(end - start, 0x90); // 0x90 = xchg eax, eax = nop
-                            span.code.splice(start..end, asm.into_iter());
-                            replaced += 1;
-                        }
-                        Err(_) => {
-                            warn!(
-                                "Failed to assemble replacement:\n{}\n(pattern location: 0x{:x})",
-                                replacement_asm,
-                                span.vaddr + start
-                            );
-                        }
+                }
+                None => {
+                    for candidate in &candidates {
+                        apply(candidate);
                     }
                 }
             }
 
-            if opt.verbosity >= 1 {
-                println!(
-                    "Pattern {} was found {} times and replaced {} times",
-                    pattern_n, found, replaced
-                );
-            }
+            for &i in &scan {
+                let (pattern_n, pattern, _) = selected[i];
+                let label = pattern.label(pattern_n);
+                if opt.verbosity >= 1 {
+                    println!(
+                        "Pattern {} was found {} times: {}",
+                        label, found[i], statistics[i]
+                    );
+                }
 
-            found_total += found;
-            replaced_total += replaced;
+                if statistics[i].applied > 0 {
+                    *applied_passes.entry(pattern_n).or_insert(0) += 1;
+                    applied_patterns.push((pattern_n, label, statistics[i].applied));
+                }
 
-            found_total_total += found;
-            replaced_total_total += replaced;
+                found_total += found[i];
+                statistics_total += statistics[i];
+
+                found_total_total += found[i];
+                statistics_total_total += statistics[i];
+            }
         }
 
         println!(
             "This pass: found {} pattern occurences of which {} were sucessfully replaced",
-            found_total, replaced_total
+            found_total, statistics_total.applied
         );
+        if opt.instruction_boundaries {
+            println!(
+                "Discarded {} candidates which didn't start at an instruction boundary",
+                discarded_total
+            );
+        }
+
+        // Only rewrites which were actually applied may enable further matches
+        if statistics_total.applied == 0 {
+            break;
+        }
+        // Replacements which rewrite the code into itself (e.g. a NOP replaced by the same NOP) or
+        // into each other (A -> B -> A) would otherwise be applied forever
+        let repeated = !insert_code_state(&mut earlier_states, &spans);
+        if repeated {
+            println!(
+                "Pass {} returned the code to an earlier state; stopping",
+                pass_n
+            );
+        } else if pass_n >= opt.max_passes {
+            println!("Reached the maximum of {} passes; stopping", opt.max_passes);
+        }
+        if repeated || pass_n >= opt.max_passes {
+            println!("Patterns which were still applied in the last pass:");
+            for (pattern_n, label, applied) in &applied_patterns {
+                println!(
+                    "    {}: {} replacements (applied in {} of {} passes)",
+                    label, applied, applied_passes[pattern_n], pass_n
+                );
+            }
+            break;
+        }
     }
 
     println!("=============================================");
     println!(
         "Total: found {} pattern occurences of which {} were sucessfully replaced",
-        found_total_total, replaced_total_total
+        found_total_total, statistics_total_total.applied
     );
+    println!("Replacements: {}", statistics_total_total);
+
+    if let Some(code_caves) = rewriter.code_caves() {
+        println!(
+            "Placed {} replacements in code caves ({} bytes left)",
+            trampolines.len(),
+            code_caves.free()
+        );
+        if opt.verbosity >= 2 {
+            for trampoline in &trampolines {
+                println!(
+                    "    0x{:x} - 0x{:x} -> 0x{:x} - 0x{:x}",
+                    trampoline.site.start,
+                    trampoline.site.end,
+                    trampoline.cave.start,
+                    trampoline.cave.end
+                );
+            }
+        }
+    }
 
     if opt.no_output {
         return;
     }
 
-    let output = opt.output.unwrap();
+    if opt.jump_chains {
+        simplify_jump_chains(opt, &mut spans, &function_starts, filler_set, &disassembler);
+    }
+    if opt.compact {
+        compact(
+            opt,
+            &mut spans,
+            &function_starts,
+            &relocation_targets,
+            filler_set,
+            &disassembler,
+        );
+    }
 
     for span in spans {
-        let offset = span.range_in_file.start;
-        deobfuscated_binary.splice(
-            offset..(offset + span.code.len()),
-            span.code.iter().cloned(),
-        );
+        span.write_to(&mut deobfuscated_binary);
     }
 
     if output.exists() {
@@ -219,37 +696,158 @@ fn main() {
     println!("Wrote deobfuscated binary to {}", output.display());
 }
 
-// fn deobfuscate(spans: &[Span], bi)
-
-struct Span {
-    range_in_file: Range<usize>,
-    vaddr: usize,
-    code: Vec<u8>,
-}
-
-fn get_code_segments<'a>(pe: PE<'_>, buffer: &'a [u8]) -> Vec<Span> {
-    use goblin::pe::section_table::IMAGE_SCN_CNT_CODE;
-
-    // println!("{:?}", pe.entry);
-    let mut vec = Vec::new();
-    for section in pe.sections {
-        // println!("{:#x?}", section);
-        // println!("{:?}", section.characteristics & IMAGE_SCN_CNT_CODE > 0);
-        // println!(
-        //     "{:08} {:032b}",
-        //     section.name().unwrap(),
-        //     section.characteristics
-        // );
-        if section.characteristics & IMAGE_SCN_CNT_CODE > 0 {
-            let range_in_file = section.pointer_to_raw_data as usize
-                ..(section.pointer_to_raw_data + section.size_of_raw_data) as usize;
-            let code = &buffer[range_in_file.clone()];
-            vec.push(Span {
-                range_in_file,
-                code: code.to_vec(),
-                vaddr: section.virtual_address as usize + pe.image_base,
-            })
+/// Adds the code of all spans to the earlier states and returns whether it wasn't among them yet
+///
+/// The states are looked up by their hash but the code is compared as hashes may collide.
+fn insert_code_state(
+    earlier_states: &mut FxHashMap<u64, Vec<Vec<Vec<u8>>>>,
+    spans: &[Span],
+) -> bool {
+    let code = spans
+        .iter()
+        .map(|span| span.code.clone())
+        .collect::<Vec<_>>();
+    let states = earlier_states
+        .entry(fxhash::hash64(&code))
+        .or_insert_with(Vec::new);
+    if states.contains(&code) {
+        false
+    } else {
+        states.push(code);
+        true
+    }
+}
+
+/// Matches the pattern against all spans and returns the candidates for replacement and the number
+/// of discarded matches
+fn find_candidates(
+    opt: &Opt,
+    spans: &[Span],
+    i: usize,
+    pattern_n: usize,
+    pattern: &ObfuscationPattern,
+    obfuscation_pattern_matcher: &ObfuscationPatternMatcher,
+    constraints: &MatchConstraints<'_>,
+) -> (Vec<Candidate>, usize) {
+    let label = pattern.label(pattern_n);
+    println!("Searching for pattern {}...", label);
+
+    let mut candidates = Vec::new();
+    let mut discarded = 0;
+    for (span_index, span) in spans.iter().enumerate() {
+        let result = obfuscation_pattern_matcher.match_against_constrained(
+            &span.code,
+            span.vaddr as u64,
+            constraints,
+        );
+        discarded += result.discarded;
+        for pattern_match in result.matches {
+            let span_vaddr = span.vaddr;
+
+            if opt.verbosity >= 2 {
+                println!(
+                    "Found pattern {} (#{}): 0x{:x} - 0x{:x}",
+                    label,
+                    candidates.len() + 1,
+                    pattern_match.start() + span_vaddr,
+                    pattern_match.end() + span_vaddr
+                );
+                for (name, range) in pattern_match.blocks() {
+                    println!(
+                        "    block {}: 0x{:x} - 0x{:x}",
+                        name,
+                        range.start + span_vaddr,
+                        range.end + span_vaddr
+                    );
+                }
+            }
+
+            candidates.push(Candidate {
+                pattern: i,
+                priority: pattern.priority(),
+                span_index,
+                pattern_match,
+            });
         }
     }
-    vec
+    (candidates, discarded)
+}
+
+fn simplify_jump_chains(
+    opt: &Opt,
+    spans: &mut [Span],
+    function_starts: &[u64],
+    filler_set: &FillerSet,
+    disassembler: &Disassembler,
+) {
+    // Only instructions reachable from the known function starts are trusted
+    let referrers: Vec<_> = ControlFlowGraph::recover(spans, function_starts, disassembler)
+        .instructions()
+        .values()
+        .cloned()
+        .collect();
+    let report = jump_chain::simplify(spans, &referrers, filler_set, disassembler);
+    println!(
+        "Retargeted {} branches to the end of their jump chain ({} in loops, {} didn't fit)",
+        report.retargeted.len(),
+        report.cycles,
+        report.failed
+    );
+    if opt.verbosity >= 2 {
+        for (address, old, new) in &report.retargeted {
+            println!("    0x{:x}: 0x{:x} -> 0x{:x}", address, old, new);
+        }
+    }
+}
+
+fn compact(
+    opt: &Opt,
+    spans: &mut [Span],
+    function_starts: &[u64],
+    relocation_targets: &[u64],
+    filler_set: &FillerSet,
+    disassembler: &Disassembler,
+) {
+    let cfg = ControlFlowGraph::recover(spans, function_starts, disassembler);
+    // Addresses which may be reached from elsewhere must not move
+    let mut leaders = compaction::referenced_addresses(&linear_sweep(spans, disassembler));
+    leaders.extend(function_starts.iter().chain(relocation_targets));
+    let mut report = CompactionReport::default();
+    for span in spans.iter_mut() {
+        let span_report = compaction::compact(span, &cfg, &leaders, filler_set);
+        report.moved.extend(span_report.moved);
+        report.compacted_blocks += span_report.compacted_blocks;
+        report.aborted_blocks += span_report.aborted_blocks;
+    }
+    println!(
+        "Compacted {} blocks by moving {} instructions ({} blocks couldn't be compacted)",
+        report.compacted_blocks,
+        report.moved.len(),
+        report.aborted_blocks
+    );
+    if opt.verbosity >= 2 {
+        for (old, new) in &report.moved {
+            println!("    0x{:x} -> 0x{:x}", old, new);
+        }
+    }
+}
+
+/// Prints the verdict for each selected pattern and returns the number of patterns whose replacement
+/// differs
+fn verify_database(pattern_database: &PatternDatabase, filter: &PatternFilter) -> usize {
+    let mut verified = 0;
+    let mut differing = 0;
+    for (pattern_n, pattern) in pattern_database.select(filter) {
+        let verdict = verify::verify(pattern);
+        println!("Pattern {}: {}", pattern.label(pattern_n), verdict);
+        verified += 1;
+        if let Verdict::Differs { .. } = verdict {
+            differing += 1;
+        }
+    }
+    println!(
+        "{} of {} patterns differ from their replacement",
+        differing, verified
+    );
+    differing
 }
@@ -0,0 +1,262 @@
+//! Mining of frequent instruction sequences as a starting point for new patterns. The code is
+//! disassembled linearly, the instructions are generalized like by the listing importer (registers,
+//! addresses and branch targets become variables) and the most frequent n-grams are reported.
+
+use std::cmp::Reverse;
+use std::ops::RangeInclusive;
+
+use fxhash::{FxHashMap, FxHashSet};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+use crate::disassembly::DecodedInstruction;
+use crate::listing::{Generalizer, Instruction};
+use crate::pattern::*;
+
+/// Number of addresses which are listed in the description of a draft pattern
+const LISTED_ADDRESSES: usize = 8;
+
+/// Generalized instruction sequence which occurs more than once
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinedSequence {
+    /// Instructions in the pattern syntax; variables are shared between the instructions
+    pub instructions: Vec<String>,
+    /// Start addresses of the occurrences in ascending order
+    pub addresses: Vec<u64>,
+}
+
+impl MinedSequence {
+    pub fn count(&self) -> usize {
+        self.addresses.len()
+    }
+
+    /// Disabled draft pattern (tagged `draft` and `mined`) without a replacement. `n` is used in
+    /// its name. Returns `None` if an instruction isn't valid in a pattern.
+    pub fn draft(&self, n: usize) -> Option<ObfuscationPattern> {
+        let pattern = self
+            .instructions
+            .iter()
+            .map(|instruction| instruction.parse())
+            .collect::<Result<Vec<PatternElement>, _>>()
+            .ok()?;
+        let mut addresses: Vec<_> = self
+            .addresses
+            .iter()
+            .take(LISTED_ADDRESSES)
+            .map(|address| format!("0x{:x}", address))
+            .collect();
+        if self.count() > LISTED_ADDRESSES {
+            addresses.push("...".to_string());
+        }
+
+        let mut pattern = ObfuscationPattern::new(pattern, Vec::new());
+        pattern.set_metadata(PatternMetadata {
+            name: Some(format!("Mined sequence {}", n)),
+            description: Some(format!(
+                "Occurs {} times at {}",
+                self.count(),
+                addresses.join(", ")
+            )),
+            tags: vec!["draft".to_string(), "mined".to_string()],
+            ..PatternMetadata::default()
+        });
+        pattern.set_enabled(false);
+        Some(pattern)
+    }
+}
+
+/// Counts the generalized sequences of `instructions` (the result of a linear sweep) whose length
+/// is in `lengths` and returns the ones which occur more than once, most frequent first.
+///
+/// Filler is skipped. Sequences don't extend past an instruction which doesn't fall through, a gap
+/// in the addresses, padding (`int3` or zero bytes) or an instruction which can't be generalized.
+/// A sequence which is always preceded or followed by the same instruction is only reported in its
+/// longer form.
+pub fn mine(
+    instructions: &[DecodedInstruction],
+    lengths: RangeInclusive<usize>,
+    filler_set: &FillerSet,
+) -> Vec<MinedSequence> {
+    let (min_length, max_length) = (*lengths.start(), *lengths.end());
+    let mut occurrences: FxHashMap<Vec<String>, Vec<u64>> = FxHashMap::default();
+    for run in runs(instructions, filler_set) {
+        for start in 0..run.len() {
+            let mut generalizer = Generalizer::default();
+            let mut sequence = Vec::new();
+            for (_, instruction) in run[start..].iter().take(max_length) {
+                sequence.push(generalizer.generalize(instruction));
+                if sequence.len() >= min_length {
+                    occurrences
+                        .entry(sequence.clone())
+                        .or_default()
+                        .push(run[start].0);
+                }
+            }
+        }
+    }
+
+    let mut redundant = FxHashSet::default();
+    for (sequence, addresses) in &occurrences {
+        if sequence.len() <= min_length {
+            continue;
+        }
+        let prefix = sequence[..sequence.len() - 1].to_vec();
+        let suffix = canonical(&sequence[1..]);
+        for shorter in &[prefix, suffix] {
+            if occurrences.get(shorter).map(Vec::len) == Some(addresses.len()) {
+                redundant.insert(shorter.clone());
+            }
+        }
+    }
+
+    let mut sequences: Vec<_> = occurrences
+        .into_iter()
+        .filter(|(sequence, addresses)| addresses.len() > 1 && !redundant.contains(sequence))
+        .map(|(instructions, mut addresses)| {
+            addresses.sort();
+            MinedSequence {
+                instructions,
+                addresses,
+            }
+        })
+        .collect();
+    sequences.sort_by(|a, b| {
+        (
+            Reverse(a.count()),
+            Reverse(a.instructions.len()),
+            &a.instructions,
+        )
+            .cmp(&(
+                Reverse(b.count()),
+                Reverse(b.instructions.len()),
+                &b.instructions,
+            ))
+    });
+    sequences
+}
+
+/// Splits the instructions into runs of straight-line code which can be generalized
+fn runs(
+    instructions: &[DecodedInstruction],
+    filler_set: &FillerSet,
+) -> Vec<Vec<(u64, Instruction)>> {
+    let mut runs = vec![Vec::new()];
+    let mut end = None;
+    for decoded in instructions {
+        let bytes = decoded.bytes();
+        let is_padding =
+            bytes.iter().all(|&byte| byte == 0) || bytes.iter().all(|&byte| byte == 0xCC);
+        if end != Some(decoded.address()) || is_padding {
+            runs.push(Vec::new());
+        }
+        end = Some(decoded.end());
+        if is_padding
+            || filler_set
+                .fillers()
+                .iter()
+                .any(|filler| filler.as_slice() == bytes)
+        {
+            continue;
+        }
+        match Instruction::parse(&decoded.to_string()) {
+            Some(instruction) => runs
+                .last_mut()
+                .unwrap()
+                .push((decoded.address(), instruction)),
+            None => runs.push(Vec::new()),
+        }
+        if !decoded.falls_through() {
+            runs.push(Vec::new());
+        }
+    }
+    runs.retain(|run| !run.is_empty());
+    runs
+}
+
+/// Renumbers the variables in the order of their first use (i.e. generalizes the instructions anew)
+fn canonical(instructions: &[String]) -> Vec<String> {
+    lazy_static! {
+        static ref VARIABLE: Regex = Regex::new(r"\$(reg|num|label):([a-z])\d+").unwrap();
+    }
+    let mut names: FxHashMap<String, String> = FxHashMap::default();
+    let mut counts: FxHashMap<String, usize> = FxHashMap::default();
+    let mut renamed = Vec::new();
+    for instruction in instructions {
+        let instruction = VARIABLE.replace_all(instruction, |captures: &Captures<'_>| {
+            names
+                .entry(captures[0].to_string())
+                .or_insert_with(|| {
+                    let count = counts.entry(captures[1].to_string()).or_insert(0);
+                    *count += 1;
+                    format!("${}:{}{}", &captures[1], &captures[2], count)
+                })
+                .clone()
+        });
+        renamed.push(instruction.into_owned());
+    }
+    renamed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembly::Disassembler;
+
+    #[test]
+    fn mine_sequences() {
+        let code = [
+            0x55, // push rbp
+            0x5D, // pop rbp
+            0x90, // nop
+            0x53, // push rbx
+            0x5B, // pop rbx
+            0xC3, // ret
+            0xCC, 0xCC, // int3
+            0x56, // push rsi
+            0x5E, // pop rsi
+            0xC3, // ret
+        ];
+        let instructions = Disassembler::new().linear_sweep(&code, 0x1000);
+        let sequences = mine(&instructions, 2..=3, &FillerSet::default());
+        let sequence = |instructions: &[&str], addresses: &[u64]| MinedSequence {
+            instructions: instructions.iter().map(|s| s.to_string()).collect(),
+            addresses: addresses.to_vec(),
+        };
+        // `pop $reg:r1; ret` is always preceded by a push of the same register
+        assert_eq!(
+            sequences,
+            vec![
+                sequence(&["push $reg:r1", "pop $reg:r1"], &[0x1000, 0x1003, 0x1008]),
+                sequence(&["push $reg:r1", "pop $reg:r1", "ret"], &[0x1003, 0x1008]),
+            ]
+        );
+        assert!(!sequences[0].draft(1).unwrap().is_enabled());
+    }
+
+    #[test]
+    fn one_number_variable_per_instruction() {
+        // mov qword ptr [rip + 0x1000], 0x12345; ret
+        let store = [
+            0x48, 0xC7, 0x05, 0x00, 0x10, 0x00, 0x00, 0x45, 0x23, 0x01, 0x00, 0xC3,
+        ];
+        let code: Vec<u8> = store.iter().chain(&store).cloned().collect();
+        let instructions = Disassembler::new().linear_sweep(&code, 0x1000);
+        let sequences = mine(&instructions, 2..=2, &FillerSet::default());
+        assert_eq!(sequences.len(), 1);
+        assert_eq!(
+            sequences[0].instructions,
+            vec!["mov qword ptr [rip + $num:n1], 0x12345", "ret"]
+        );
+    }
+
+    #[test]
+    fn canonical_variables() {
+        assert_eq!(
+            canonical(&[
+                "mov $reg:r2, $num:n2".to_string(),
+                "add $reg:r3, $reg:r2".to_string(),
+            ]),
+            vec!["mov $reg:r1, $num:n1", "add $reg:r2, $reg:r1"]
+        );
+    }
+}
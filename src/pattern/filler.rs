@@ -0,0 +1,146 @@
+use std::cmp::Reverse;
+
+use failure::Fail;
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum FillerError {
+    #[fail(display = "the filler set has no single-byte filler so it can't pad every length")]
+    NoSingleByteFiller,
+}
+
+/// Byte sequences which don't have any effect (i.e. NOPs). Filler is allowed in between the
+/// instructions of a pattern and is used to pad replacements which are shorter than the original.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FillerSet {
+    /// Sorted by length in descending order
+    fillers: Vec<Vec<u8>>,
+}
+
+impl FillerSet {
+    /// Fails if none of the fillers is a single byte long since some lengths couldn't be padded
+    pub fn new(mut fillers: Vec<Vec<u8>>) -> Result<FillerSet, FillerError> {
+        fillers.retain(|filler| !filler.is_empty());
+        if !fillers.iter().any(|filler| filler.len() == 1) {
+            return Err(FillerError::NoSingleByteFiller);
+        }
+        let mut unique = Vec::new();
+        for filler in fillers {
+            if !unique.contains(&filler) {
+                unique.push(filler);
+            }
+        }
+        // Stable sort so the order of equally long fillers is preserved
+        unique.sort_by_key(|filler| Reverse(filler.len()));
+        Ok(FillerSet { fillers: unique })
+    }
+
+    pub fn fillers(&self) -> &[Vec<u8>] {
+        &self.fillers
+    }
+
+    /// Regex which matches any sequence of fillers (including an empty one)
+    pub fn regex(&self) -> String {
+        let alternatives = self
+            .fillers
+            .iter()
+            .map(|filler| {
+                filler
+                    .iter()
+                    .map(|byte| format!(r"\x{:02x}", byte))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>();
+        format!("(?:{})*", alternatives.join("|"))
+    }
+
+    /// Returns the lengths of the fillers which `bytes` start with
+    pub fn lengths_at<'a>(&'a self, bytes: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        self.fillers
+            .iter()
+            .filter(move |filler| bytes.starts_with(filler))
+            .map(|filler| filler.len())
+    }
+
+    /// Returns `length` bytes of filler. Uses as few fillers as possible.
+    pub fn pad(&self, length: usize) -> Vec<u8> {
+        let mut padding = Vec::with_capacity(length);
+        while padding.len() < length {
+            let remaining = length - padding.len();
+            // There always is a single-byte filler
+            let filler = self
+                .fillers
+                .iter()
+                .find(|filler| filler.len() <= remaining)
+                .unwrap();
+            padding.extend_from_slice(filler);
+        }
+        padding
+    }
+}
+
+impl Default for FillerSet {
+    /// The recommended multi-byte NOPs from the Intel SDM and some other NOP encodings emitted by
+    /// compilers
+    fn default() -> FillerSet {
+        FillerSet::new(vec![
+            vec![0x90],
+            vec![0x66, 0x90],
+            vec![0x0F, 0x1F, 0x00],
+            vec![0x0F, 0x1F, 0x40, 0x00],
+            vec![0x0F, 0x1F, 0x44, 0x00, 0x00],
+            vec![0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+            vec![0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00],
+            vec![0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+            vec![0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+            // nop word ptr cs:[rax + rax]
+            vec![0x66, 0x2E, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+            // xchg rax, rax
+            vec![0x48, 0x90],
+            // lea rsi, [rsi (+ 0)]
+            vec![0x48, 0x8D, 0x36],
+            vec![0x48, 0x8D, 0x76, 0x00],
+            vec![0x48, 0x8D, 0xB6, 0x00, 0x00, 0x00, 0x00],
+            // lea rdi, [rdi (+ 0)]
+            vec![0x48, 0x8D, 0x3F],
+            vec![0x48, 0x8D, 0x7F, 0x00],
+            vec![0x48, 0x8D, 0xBF, 0x00, 0x00, 0x00, 0x00],
+        ])
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding() {
+        let filler_set = FillerSet::default();
+        assert!(filler_set.pad(0).is_empty());
+        assert_eq!(filler_set.pad(1), vec![0x90]);
+        assert_eq!(filler_set.pad(3), vec![0x0F, 0x1F, 0x00]);
+        assert_eq!(
+            filler_set.pad(11),
+            vec![0x66, 0x2E, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x90]
+        );
+        assert_eq!(
+            FillerSet::new(vec![vec![0x66, 0x90], vec![0xCC]])
+                .unwrap()
+                .pad(3),
+            vec![0x66, 0x90, 0xCC]
+        );
+        assert_eq!(
+            FillerSet::new(vec![vec![0x66, 0x90]]),
+            Err(FillerError::NoSingleByteFiller)
+        );
+    }
+
+    #[test]
+    fn filler_regex() {
+        let regex =
+            regex::bytes::Regex::new(&format!("(?s-u)^{}$", FillerSet::default().regex())).unwrap();
+        assert!(regex.is_match(&[]));
+        assert!(regex.is_match(&[0x90, 0x66, 0x90, 0x0F, 0x1F, 0x00, 0x48, 0x8D, 0x36]));
+        assert!(!regex.is_match(&[0x90, 0x66]));
+    }
+}
@@ -1,193 +1,903 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
 use regex::bytes::Regex;
 
+use crate::disassembly::{DecodedInstruction, Disassembler};
 use crate::pattern::*;
+use crate::x86::{BranchKind, Gpr};
 
 #[derive(Debug, Clone)]
 pub struct ObfuscationPatternMatcher {
-    instruction_pattern_matchers: Vec<InstructionPatternMatcher>,
+    element_matchers: Vec<ElementMatcher>,
+    /// Blocks which are matched at the targets of the labels with the same name
+    blocks: Vec<(String, Vec<ElementMatcher>)>,
+    /// Filler allowed in between the elements
+    filler_set: FillerSet,
+    /// Matches the pattern up to the first wildcard. This is only used to find candidate positions
+    /// which are then matched element by element.
     regex: Regex,
+    /// Register variables and the registers they may not be
+    excluded: Vec<(String, Register)>,
+}
+
+/// A match of an obfuscation pattern. The replacement replaces `start..end`; the blocks are only
+/// reported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternMatch {
+    variables: Vec<InstantiatedVariable>,
+    start: usize,
+    end: usize,
+    blocks: Vec<(String, Range<usize>)>,
+}
+
+impl PatternMatch {
+    pub fn variables(&self) -> &[InstantiatedVariable] {
+        &self.variables
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// The matched blocks and their location
+    pub fn blocks(&self) -> &[(String, Range<usize>)] {
+        &self.blocks
+    }
+}
+
+/// Knowledge about the code which restricts where patterns may match
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MatchConstraints<'a> {
+    /// Start addresses of basic blocks. A match (and each of its blocks) may start at a leader but
+    /// mustn't contain one as the code could be entered in the middle.
+    pub leaders: Option<&'a BTreeSet<u64>>,
+    /// Addresses of all decoded instructions. A match may only start at one of them so it can't
+    /// start in the middle of another instruction.
+    pub instruction_starts: Option<&'a BTreeSet<u64>>,
+}
+
+/// Matches which fulfill the constraints
+#[derive(Debug, Clone, Default)]
+pub struct ConstrainedMatches {
+    pub matches: Vec<PatternMatch>,
+    /// Number of candidates which were discarded as they don't start at an instruction boundary
+    pub discarded: usize,
+}
+
+impl<'a> MatchConstraints<'a> {
+    /// Whether code at `range` which is located at `address` contains a leader after its start
+    fn crosses_leader(&self, address: u64, range: &Range<usize>) -> bool {
+        match self.leaders {
+            Some(leaders) if range.end > range.start + 1 => leaders
+                .range(address + range.start as u64 + 1..address + range.end as u64)
+                .next()
+                .is_some(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ElementMatcher {
+    Instruction(InstructionPatternMatcher),
+    Branch(BranchMatcher),
+    Sequence(Vec<ElementMatcher>),
+    AnyOf(Vec<ElementMatcher>),
+    Repeat {
+        body: Vec<ElementMatcher>,
+        min: usize,
+        max: usize,
+    },
+    Wildcard(WildcardMatcher),
+}
+
+/// Called with the end of an element's match and checks whether the rest of the pattern matches
+type Continuation<'c, 's> = dyn FnMut(usize, &mut MatchState<'s>) -> Option<usize> + 'c;
+
+impl ElementMatcher {
+    fn new(element: PatternElement) -> Result<ElementMatcher, PatternError> {
+        let new_all = |elements: Vec<PatternElement>| {
+            elements
+                .into_iter()
+                .map(ElementMatcher::new)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(match element {
+            PatternElement::Instruction(instruction_pattern) => {
+                if instruction_pattern
+                    .variables()
+                    .iter()
+                    .any(|variable| variable.typee() == VariableType::Label)
+                {
+                    ElementMatcher::Branch(BranchMatcher::new(instruction_pattern)?)
+                } else {
+                    ElementMatcher::Instruction(InstructionPatternMatcher::new(
+                        instruction_pattern,
+                    )?)
+                }
+            }
+            PatternElement::Sequence(elements) => ElementMatcher::Sequence(new_all(elements)?),
+            PatternElement::AnyOf { any_of } => ElementMatcher::AnyOf(new_all(any_of)?),
+            PatternElement::Repeat { repeat, min, max } => {
+                if min > max || max == 0 {
+                    return Err(PatternError::InvalidRepetition(min, max));
+                }
+                ElementMatcher::Repeat {
+                    body: new_all(repeat)?,
+                    min,
+                    max,
+                }
+            }
+            PatternElement::Wildcard { wildcard } => {
+                ElementMatcher::Wildcard(WildcardMatcher::new(wildcard)?)
+            }
+        })
+    }
+
+    /// Regex for the element or `None` if the element can't be expressed as regex
+    fn regex(&self, filler_regex: &str) -> Option<String> {
+        let sequence_regex = |elements: &[ElementMatcher]| {
+            elements
+                .iter()
+                .map(|element| element.regex(filler_regex))
+                .collect::<Option<Vec<_>>>()
+                .map(|regexes| format!("(?:{})", regexes.join(filler_regex)))
+        };
+        match self {
+            ElementMatcher::Instruction(ipm) => Some(ipm.regex.clone()),
+            ElementMatcher::Branch(branch_matcher) => Some(branch_matcher.regex()),
+            ElementMatcher::Sequence(elements) => sequence_regex(elements),
+            ElementMatcher::AnyOf(alternatives) => alternatives
+                .iter()
+                .map(|alternative| alternative.regex(filler_regex))
+                .collect::<Option<Vec<_>>>()
+                .map(|regexes| format!("(?:{})", regexes.join("|"))),
+            ElementMatcher::Repeat { body, min, max } => sequence_regex(body)
+                .map(|body| format!("(?:{}{}){{{},{}}}", body, filler_regex, min, max)),
+            ElementMatcher::Wildcard(_) => None,
+        }
+    }
+
+    fn instruction_patterns(&self, instruction_patterns: &mut Vec<InstructionPattern>) {
+        match self {
+            ElementMatcher::Instruction(ipm) => instruction_patterns.push(ipm.pattern.clone()),
+            ElementMatcher::Branch(branch_matcher) => {
+                instruction_patterns.push(branch_matcher.pattern.clone())
+            }
+            ElementMatcher::Sequence(elements)
+            | ElementMatcher::AnyOf(elements)
+            | ElementMatcher::Repeat { body: elements, .. } => {
+                for element in elements {
+                    element.instruction_patterns(instruction_patterns);
+                }
+            }
+            ElementMatcher::Wildcard(_) => {}
+        }
+    }
+
+    fn labels<'a>(&'a self, labels: &mut Vec<&'a str>) {
+        match self {
+            ElementMatcher::Branch(branch_matcher) => labels.push(&branch_matcher.label),
+            ElementMatcher::Sequence(elements)
+            | ElementMatcher::AnyOf(elements)
+            | ElementMatcher::Repeat { body: elements, .. } => {
+                for element in elements {
+                    element.labels(labels);
+                }
+            }
+            ElementMatcher::Instruction(_) | ElementMatcher::Wildcard(_) => {}
+        }
+    }
+
+    fn is_wildcard(&self) -> bool {
+        match self {
+            ElementMatcher::Wildcard(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the element can match without any instruction
+    fn can_be_empty(&self) -> bool {
+        match self {
+            ElementMatcher::Instruction(_) | ElementMatcher::Branch(_) => false,
+            ElementMatcher::Sequence(elements) => elements.iter().all(ElementMatcher::can_be_empty),
+            ElementMatcher::AnyOf(alternatives) => {
+                alternatives.iter().any(ElementMatcher::can_be_empty)
+            }
+            ElementMatcher::Repeat { body, min, .. } => {
+                *min == 0 || body.iter().all(ElementMatcher::can_be_empty)
+            }
+            ElementMatcher::Wildcard(_) => true,
+        }
+    }
 }
 
 impl ObfuscationPatternMatcher {
-    pub fn new(
-        instruction_patterns: Vec<InstructionPattern>,
+    pub fn new<E: Into<PatternElement>>(
+        elements: Vec<E>,
+    ) -> Result<ObfuscationPatternMatcher, PatternError> {
+        Self::with_filler_set(elements, FillerSet::default())
+    }
+
+    pub fn with_filler_set<E: Into<PatternElement>>(
+        elements: Vec<E>,
+        filler_set: FillerSet,
+    ) -> Result<ObfuscationPatternMatcher, PatternError> {
+        Self::build(
+            elements.into_iter().map(Into::into).collect(),
+            BTreeMap::new(),
+            filler_set,
+        )
+    }
+
+    /// Matcher for the pattern including its blocks
+    pub fn for_pattern(
+        pattern: &ObfuscationPattern,
+        filler_set: FillerSet,
+    ) -> Result<ObfuscationPatternMatcher, PatternError> {
+        let mut matcher = Self::build(
+            pattern.pattern().to_vec(),
+            pattern.blocks().clone(),
+            filler_set,
+        )?;
+        matcher.excluded = pattern.exclusions()?;
+        Ok(matcher)
+    }
+
+    fn build(
+        elements: Vec<PatternElement>,
+        blocks: BTreeMap<String, Vec<PatternElement>>,
+        filler_set: FillerSet,
     ) -> Result<ObfuscationPatternMatcher, PatternError> {
-        let instruction_pattern_matchers = instruction_patterns
+        let new_all = |elements: Vec<PatternElement>| {
+            elements
+                .into_iter()
+                .map(ElementMatcher::new)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let element_matchers = new_all(elements)?;
+        let blocks = blocks
             .into_iter()
-            .map(InstructionPatternMatcher::new)
-            .collect::<Result<Vec<_>, _>>()?;
+            .map(|(name, elements)| Ok((name, new_all(elements)?)))
+            .collect::<Result<Vec<_>, PatternError>>()?;
+
+        let mut labels = Vec::new();
+        for element_matcher in element_matchers
+            .iter()
+            .chain(blocks.iter().flat_map(|(_, elements)| elements))
+        {
+            element_matcher.labels(&mut labels);
+        }
+        if let Some((name, _)) = blocks.iter().find(|(name, _)| !labels.contains(&&name[..])) {
+            return Err(PatternError::InvalidLabel(format!(
+                "no branch refers to block {}",
+                name
+            )));
+        }
+
+        let starts_or_ends_with_wildcard = match (element_matchers.first(), element_matchers.last())
+        {
+            (Some(first), Some(last)) => first.is_wildcard() || last.is_wildcard(),
+            _ => false,
+        };
+        if starts_or_ends_with_wildcard {
+            return Err(PatternError::InvalidWildcard(
+                "a pattern may not start or end with a wildcard".to_string(),
+            ));
+        }
+        // The match has to start with an instruction
+        if element_matchers
+            .first()
+            .map(ElementMatcher::can_be_empty)
+            .unwrap_or(false)
+        {
+            return Err(PatternError::EmptyFirstElement);
+        }
 
         // regex flags:
         //    s: allow . to match \n
         //   -u: disable unicode support (allow matches even when not at a unicode boundary)
+        let filler_regex = filler_set.regex();
         let regex = format!(
             "(?s-u){}",
-            instruction_pattern_matchers
+            element_matchers
                 .iter()
-                .map(|ipm| &*ipm.regex)
+                .map(|element_matcher| element_matcher.regex(&filler_regex))
+                .take_while(Option::is_some)
+                .map(Option::unwrap)
                 .collect::<Vec<_>>()
-                .join(r"\x90*") // nop
+                .join(&filler_regex)
         );
         debug!("obfuscation pattern regex: {}", regex);
-        let regex = Regex::new(&regex).unwrap();
+        // Fails if the regex gets too large (e.g. because of a repetition with a huge maximum)
+        let regex =
+            Regex::new(&regex).map_err(|error| PatternError::RegexFailed(error.to_string()))?;
 
         Ok(ObfuscationPatternMatcher {
-            instruction_pattern_matchers,
+            element_matchers,
+            blocks,
+            filler_set,
             regex,
+            excluded: Vec::new(),
         })
     }
 
+    /// Returns all instruction patterns which are part of the pattern in the order they appear in
     pub fn instruction_patterns(&self) -> Vec<InstructionPattern> {
-        self.instruction_pattern_matchers
-            .iter()
-            .map(|ipm| ipm.pattern.clone())
-            .collect()
+        let mut instruction_patterns = Vec::new();
+        for element_matcher in &self.element_matchers {
+            element_matcher.instruction_patterns(&mut instruction_patterns);
+        }
+        instruction_patterns
+    }
+
+    /// Returns the found matches; positions are relative to the start of `bytes`
+    pub fn match_against(&self, bytes: &[u8]) -> Vec<PatternMatch> {
+        self.match_against_at(bytes, 0)
+    }
+
+    /// Same as `match_against` for `bytes` which are located at `address`. The address is used
+    /// when disassembling instructions matched by wildcards and to locate branch targets.
+    pub fn match_against_at(&self, bytes: &[u8], address: u64) -> Vec<PatternMatch> {
+        self.match_against_constrained(bytes, address, &MatchConstraints::default())
+            .matches
     }
 
-    /// Returns the found matches where each match contains information about the matched variables,
-    /// the start position, and the end position
-    pub fn match_against(&self, bytes: &[u8]) -> Vec<(Vec<InstantiatedVariable>, usize, usize)> {
+    /// Same as `match_against_at` but only returns matches which fulfill `constraints`
+    pub fn match_against_constrained(
+        &self,
+        bytes: &[u8],
+        address: u64,
+        constraints: &MatchConstraints<'_>,
+    ) -> ConstrainedMatches {
         debug!("regex: {}", self.regex.as_str());
         if bytes.len() < 100 {
             debug!("match against: {:x?}", bytes);
         } else {
             debug!("match against: too long...");
         }
-        self.regex
-            .captures_iter(bytes)
-            .map(|captures| {
-                trace!("new capture ----------");
-                let whole_match = captures.get(0).unwrap();
-                // need to offset the capture group purpose index as the indices in the
-                // `InstructionPatternMatcher`s are only valid in their own capture group
-                let mut capture_group_offset = 1;
-
-                struct InstantiatedVariableStore(Vec<InstantiatedVariable>);
-                impl InstantiatedVariableStore {
-                    fn try_add(&mut self, new_variable: InstantiatedVariable) -> bool {
-                        // TODO: change to better data structure?
-                        match self.0.iter().find(|var| var.name() == new_variable.name()) {
-                            Some(existing) => {
-                                if &new_variable != existing {
-                                    info!("Rejected match because variable value changed; previous: {}; now: {}", existing.value(), new_variable.value());
-                                    false
-                                } else {
-                                    true
-                                }
-                            }
-                            None => {
-                                self.0.push(new_variable);
-                                true
-                            }
-                        }
+
+        let disassembler = Disassembler::new();
+        let mut result = ConstrainedMatches::default();
+        let mut search_start = 0;
+        // A candidate which turns out not to match may overlap with a later match so the search
+        // resumes right after the start of each failed candidate
+        while let Some(candidate) = self.regex.find_at(bytes, search_start) {
+            search_start = candidate.start() + 1;
+            if let Some(instruction_starts) = constraints.instruction_starts {
+                if !instruction_starts.contains(&(address + candidate.start() as u64)) {
+                    trace!("discarding candidate at {:#x}", candidate.start());
+                    result.discarded += 1;
+                    continue;
+                }
+            }
+            trace!("new candidate at {:#x}", candidate.start());
+            let mut state = MatchState {
+                bytes,
+                address,
+                start: candidate.start(),
+                disassembler: &disassembler,
+                variables: InstantiatedVariableStore(Vec::new()),
+                deferred_untouched: Vec::new(),
+                blocks: Vec::new(),
+            };
+            let end = self.match_sequence(
+                &self.element_matchers,
+                candidate.start(),
+                &mut state,
+                &mut |end, state| {
+                    if constraints.crosses_leader(address, &(state.start..end)) {
+                        return None;
                     }
+                    self.match_blocks(state, &mut |state| {
+                        let blocks_cross_leader = state
+                            .blocks
+                            .iter()
+                            .any(|(_, range)| constraints.crosses_leader(address, range));
+                        if state.deferred_untouched_hold()
+                            && !blocks_cross_leader
+                            && !self.is_excluded(&state.variables)
+                        {
+                            Some(end)
+                        } else {
+                            None
+                        }
+                    })
+                },
+            );
+            if let Some(end) = end {
+                result.matches.push(PatternMatch {
+                    variables: state.variables.0,
+                    start: candidate.start(),
+                    end,
+                    blocks: state.blocks,
+                });
+                search_start = end.max(search_start);
+            }
+        }
+        result
+    }
+
+    /// Whether a register variable is instantiated with a register it may not be
+    fn is_excluded(&self, variables: &InstantiatedVariableStore) -> bool {
+        self.excluded.iter().any(|(name, excluded)| {
+            variables.0.iter().any(|variable| match variable {
+                InstantiatedVariable::Register(variable_name, register) => {
+                    variable_name == name && register == excluded
                 }
+                _ => false,
+            })
+        })
+    }
 
-                let mut instantiated_variables = InstantiatedVariableStore(Vec::new());
-
-                'outer: for k in 0..self.instruction_pattern_matchers.len() {
-                    trace!("capture_group_offset: {:?}", capture_group_offset);
-                    for i in 1.. {
-                        // Iterate through the different encoding capture groups of this instruction
-                        // pattern
-                        // This will definitely terminate as regex.captures(bytes) only returns `Some` if
-                        // it matched and then an encoding capture group must have participated in the match
-                        if let CaptureGroupPurpose::NewEncoding(ref register_mappings) =
-                            self.instruction_pattern_matchers[k].capture_group_purposes[i]
-                        {
-                            trace!("getting capture group {}", i + capture_group_offset);
-                            if let Some(instruction_match) = captures.get(i + capture_group_offset)
-                            {
-                                // Found the matched encoding; Extract the number variables and return result
-
-                                for j in (i + 1).. {
-                                    trace!(
-                                        "inner getting capture group {}",
-                                        j + capture_group_offset
-                                    );
-                                    match self.instruction_pattern_matchers[k]
-                                        .capture_group_purposes
-                                        .get(j)
-                                    {
-                                        None | Some(CaptureGroupPurpose::NewEncoding(_)) => {
-                                            // Also add register variable instantiations
-                                            for (variable_name, register) in register_mappings {
-                                                if !instantiated_variables.try_add(
-                                                    InstantiatedVariable::new_register(
-                                                        variable_name.to_string(),
-                                                        *register,
-                                                    ),
-                                                ) {
-                                                    return None;
-                                                }
-                                            }
-                                            // And add length variable instantiations
-                                            if let Some(length_variable) = self
-                                                .instruction_pattern_matchers[k]
-                                                .pattern
-                                                .length_variable()
-                                            {
-                                                if !instantiated_variables.try_add(
-                                                    InstantiatedVariable::new_length(
-                                                        length_variable.name().to_string(),
-                                                        instruction_match.as_bytes().len(),
-                                                    ),
-                                                ) {
-                                                    return None;
-                                                }
-                                            }
-                                            if k == self.instruction_pattern_matchers.len() - 1 {
-                                                // matched all instruction patterns; success!
-                                                return Some((
-                                                    instantiated_variables.0,
-                                                    whole_match.start(),
-                                                    whole_match.end(),
-                                                ));
-                                            } else {
-                                                capture_group_offset += self
-                                                    .instruction_pattern_matchers[k]
-                                                    .capture_group_purposes
-                                                    .len();
-                                                continue 'outer;
-                                            }
-                                        }
-                                        Some(CaptureGroupPurpose::NumberVariable(
-                                            variable_name,
-                                        )) => {
-                                            let bytes = &captures[j + capture_group_offset];
-                                            assert!(bytes.len() <= 4);
-                                            let mut value = 0;
-                                            for byte in bytes.iter().rev() {
-                                                value <<= 8;
-                                                value += u64::from(*byte);
-                                            }
-                                            if !instantiated_variables.try_add(
-                                                InstantiatedVariable::new_number(
-                                                    variable_name.to_string(),
-                                                    value,
-                                                ),
-                                            ) {
-                                                return None;
-                                            }
-                                        }
-                                        Some(CaptureGroupPurpose::WholeMatch) => unreachable!(),
-                                    }
-                                }
+    /// Matches `elements` starting at `position` and then calls `continuation` with the end of
+    /// the match. Returns the end of the whole match once the continuation succeeds. The matching
+    /// backtracks over filler lengths, alternatives, repetition counts and wildcard instruction
+    /// counts.
+    fn match_sequence<'s>(
+        &self,
+        elements: &[ElementMatcher],
+        position: usize,
+        state: &mut MatchState<'s>,
+        continuation: &mut Continuation<'_, 's>,
+    ) -> Option<usize> {
+        match elements.split_first() {
+            None => continuation(position, state),
+            Some((element, rest)) => {
+                self.match_element(element, position, state, &mut |end, state| {
+                    self.match_sequence(rest, end, state, continuation)
+                })
+            }
+        }
+    }
+
+    fn match_element<'s>(
+        &self,
+        element: &ElementMatcher,
+        position: usize,
+        state: &mut MatchState<'s>,
+        continuation: &mut Continuation<'_, 's>,
+    ) -> Option<usize> {
+        match element {
+            ElementMatcher::Instruction(ipm) => {
+                // Filler is only allowed in between elements
+                let instruction_starts = if position == state.start {
+                    vec![position]
+                } else {
+                    self.filler_ends(state.bytes, position)
+                };
+                for instruction_start in instruction_starts {
+                    let checkpoint = state.checkpoint();
+                    if let Some(end) =
+                        ipm.match_at(state.bytes, instruction_start, &mut state.variables)
+                    {
+                        if let Some(end) = continuation(end, state) {
+                            return Some(end);
+                        }
+                    }
+                    state.restore(checkpoint);
+                }
+                None
+            }
+            ElementMatcher::Branch(branch_matcher) => {
+                let instruction_starts = if position == state.start {
+                    vec![position]
+                } else {
+                    self.filler_ends(state.bytes, position)
+                };
+                for instruction_start in instruction_starts {
+                    let checkpoint = state.checkpoint();
+                    if let Some(end) = branch_matcher.match_at(instruction_start, state) {
+                        if let Some(end) = continuation(end, state) {
+                            return Some(end);
+                        }
+                    }
+                    state.restore(checkpoint);
+                }
+                None
+            }
+            ElementMatcher::Sequence(elements) => {
+                self.match_sequence(elements, position, state, continuation)
+            }
+            ElementMatcher::AnyOf(alternatives) => {
+                for alternative in alternatives {
+                    let checkpoint = state.checkpoint();
+                    if let Some(end) =
+                        self.match_element(alternative, position, state, continuation)
+                    {
+                        return Some(end);
+                    }
+                    state.restore(checkpoint);
+                }
+                None
+            }
+            ElementMatcher::Repeat { body, min, max } => {
+                self.match_repetition(body, *min, *max, 0, position, state, continuation)
+            }
+            ElementMatcher::Wildcard(wildcard_matcher) => {
+                let mut skipped = Vec::new();
+                let mut end = position;
+                loop {
+                    if skipped.len() >= wildcard_matcher.wildcard.min() {
+                        let checkpoint = state.checkpoint();
+                        if wildcard_matcher.accept(&skipped, state) {
+                            if let Some(end) = continuation(end, state) {
+                                return Some(end);
                             }
                         }
+                        state.restore(checkpoint);
                     }
+                    if skipped.len() == wildcard_matcher.wildcard.max() {
+                        return None;
+                    }
+
+                    let instruction = state
+                        .disassembler
+                        .decode(&state.bytes[end..], state.address + end as u64)?;
+                    if instruction.is_control_transfer() || !wildcard_matcher.may_skip(&instruction)
+                    {
+                        return None;
+                    }
+                    end += instruction.size();
+                    skipped.push(instruction);
                 }
-                unreachable!()
-            })
-            .filter_map(|opt| opt)
+            }
+        }
+    }
+
+    /// Matches the blocks whose labels are instantiated but which haven't been matched yet and then
+    /// calls `continuation`. Blocks may instantiate further labels.
+    fn match_blocks<'s>(
+        &self,
+        state: &mut MatchState<'s>,
+        continuation: &mut dyn FnMut(&mut MatchState<'s>) -> Option<usize>,
+    ) -> Option<usize> {
+        let next_block = self.blocks.iter().find_map(|(name, elements)| {
+            if state.blocks.iter().any(|(matched, _)| matched == name) {
+                return None;
+            }
+            state
+                .variables
+                .label(name)
+                .map(|target| (name, elements, target))
+        });
+        let (name, elements, target) = match next_block {
+            Some(next_block) => next_block,
+            None => return continuation(state),
+        };
+
+        // Only targets inside of the matched bytes can be followed
+        let position = target.checked_sub(state.address)? as usize;
+        if position >= state.bytes.len() {
+            return None;
+        }
+        let checkpoint = state.checkpoint();
+        let end = self.match_sequence(elements, position, state, &mut |end, state| {
+            state.blocks.push((name.clone(), position..end));
+            self.match_blocks(state, continuation)
+        });
+        if end.is_none() {
+            state.restore(checkpoint);
+        }
+        end
+    }
+
+    /// Matches as many repetitions of `body` as possible (greedy); `count` repetitions have
+    /// already been matched
+    #[allow(clippy::too_many_arguments)]
+    fn match_repetition<'s>(
+        &self,
+        body: &[ElementMatcher],
+        min: usize,
+        max: usize,
+        count: usize,
+        position: usize,
+        state: &mut MatchState<'s>,
+        continuation: &mut Continuation<'_, 's>,
+    ) -> Option<usize> {
+        if count < max {
+            let checkpoint = state.checkpoint();
+            let end = self.match_sequence(body, position, state, &mut |end, state| {
+                if end == position {
+                    // Empty repetitions would never terminate
+                    return None;
+                }
+                self.match_repetition(body, min, max, count + 1, end, state, continuation)
+            });
+            if end.is_some() {
+                return end;
+            }
+            state.restore(checkpoint);
+        }
+        if count >= min {
+            continuation(position, state)
+        } else {
+            None
+        }
+    }
+
+    /// Returns all positions which can be reached from `position` by skipping filler; in
+    /// ascending order
+    fn filler_ends(&self, bytes: &[u8], position: usize) -> Vec<usize> {
+        // Fillers aren't empty so a single forward scan visits every reachable position after all
+        // the positions it can be reached from
+        let mut reachable = vec![true];
+        let mut offset = 0;
+        while offset < reachable.len() {
+            if reachable[offset] {
+                for length in self.filler_set.lengths_at(&bytes[position + offset..]) {
+                    if reachable.len() <= offset + length {
+                        reachable.resize(offset + length + 1, false);
+                    }
+                    reachable[offset + length] = true;
+                }
+            }
+            offset += 1;
+        }
+        reachable
+            .iter()
+            .enumerate()
+            .filter(|&(_, &reachable)| reachable)
+            .map(|(offset, _)| position + offset)
             .collect()
     }
 }
 
+struct MatchState<'a> {
+    bytes: &'a [u8],
+    address: u64,
+    /// Start of the whole match
+    start: usize,
+    disassembler: &'a Disassembler,
+    variables: InstantiatedVariableStore,
+    /// Registers touched by wildcards which must not be the value of the register variable;
+    /// checked once all variables are instantiated
+    deferred_untouched: Vec<(String, Vec<Gpr>)>,
+    /// Matched blocks
+    blocks: Vec<(String, Range<usize>)>,
+}
+
+impl<'a> MatchState<'a> {
+    fn checkpoint(&self) -> (usize, usize, usize) {
+        (
+            self.variables.0.len(),
+            self.deferred_untouched.len(),
+            self.blocks.len(),
+        )
+    }
+
+    fn restore(&mut self, (variables, deferred_untouched, blocks): (usize, usize, usize)) {
+        self.variables.0.truncate(variables);
+        self.deferred_untouched.truncate(deferred_untouched);
+        self.blocks.truncate(blocks);
+    }
+
+    fn deferred_untouched_hold(&self) -> bool {
+        self.deferred_untouched
+            .iter()
+            .all(
+                |(variable_name, touched)| match self.variables.register(variable_name) {
+                    Some(gpr) => !touched.contains(&gpr),
+                    None => true,
+                },
+            )
+    }
+}
+
+struct InstantiatedVariableStore(Vec<InstantiatedVariable>);
+
+impl InstantiatedVariableStore {
+    fn try_add(&mut self, new_variable: InstantiatedVariable) -> bool {
+        // TODO: change to better data structure?
+        match self.0.iter().find(|var| var.name() == new_variable.name()) {
+            Some(existing) => {
+                if &new_variable != existing {
+                    info!(
+                        "Rejected match because variable value changed; previous: {}; now: {}",
+                        existing.value(),
+                        new_variable.value()
+                    );
+                    false
+                } else {
+                    true
+                }
+            }
+            None => {
+                self.0.push(new_variable);
+                true
+            }
+        }
+    }
+
+    fn register(&self, variable_name: &str) -> Option<Gpr> {
+        self.0.iter().find_map(|variable| match variable {
+            InstantiatedVariable::Register(name, register) if name == variable_name => {
+                Gpr::from_register_name(register.name())
+            }
+            _ => None,
+        })
+    }
+
+    fn label(&self, variable_name: &str) -> Option<u64> {
+        self.0.iter().find_map(|variable| match variable {
+            InstantiatedVariable::Label(name, target) if name == variable_name => Some(*target),
+            _ => None,
+        })
+    }
+}
+
+/// Matches a direct branch whose target is a label (e.g. `jz $label:taken`). The branch is decoded
+/// so the target is known regardless of the encoding.
+#[derive(Debug, Clone)]
+struct BranchMatcher {
+    pattern: InstructionPattern,
+    kind: BranchKind,
+    label: String,
+}
+
+impl BranchMatcher {
+    fn new(pattern: InstructionPattern) -> Result<BranchMatcher, PatternError> {
+        let invalid = || {
+            PatternError::InvalidLabel(format!(
+                "labels may only be used as the sole operand of a direct branch: {}",
+                pattern.pattern()
+            ))
+        };
+        let parts: Vec<_> = pattern.pattern().split_whitespace().collect();
+        let (kind, label) = match (&parts[..], pattern.variables()) {
+            ([mnemonic, _], [variable]) if variable.typee() == VariableType::Label => (
+                BranchKind::from_mnemonic(mnemonic).ok_or_else(invalid)?,
+                variable.name().to_string(),
+            ),
+            _ => return Err(invalid()),
+        };
+        Ok(BranchMatcher {
+            pattern,
+            kind,
+            label,
+        })
+    }
+
+    fn regex(&self) -> String {
+        let (rel8_opcode, rel32_opcode) = self.kind.opcodes();
+        let mut alternatives = Vec::new();
+        if let Some(opcode) = rel8_opcode {
+            alternatives.push(format!(r"\x{:02x}.", opcode));
+        }
+        let rel32_opcode = rel32_opcode
+            .iter()
+            .map(|byte| format!(r"\x{:02x}", byte))
+            .collect::<String>();
+        alternatives.push(format!("{}.{{4}}", rel32_opcode));
+        format!("(?:{})", alternatives.join("|"))
+    }
+
+    fn match_at(&self, position: usize, state: &mut MatchState<'_>) -> Option<usize> {
+        let instruction = state
+            .disassembler
+            .decode(&state.bytes[position..], state.address + position as u64)?;
+        if BranchKind::from_mnemonic(instruction.mnemonic()) != Some(self.kind) {
+            return None;
+        }
+        let target = instruction.branch_target()?;
+        if state
+            .variables
+            .try_add(InstantiatedVariable::new_label(self.label.clone(), target))
+        {
+            Some(position + instruction.size())
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WildcardMatcher {
+    wildcard: Wildcard,
+    untouched: Vec<UntouchedRegister>,
+}
+
+#[derive(Debug, Clone)]
+enum UntouchedRegister {
+    Fixed(Gpr),
+    Variable(String),
+}
+
+impl WildcardMatcher {
+    fn new(wildcard: Wildcard) -> Result<WildcardMatcher, PatternError> {
+        if wildcard.min() > wildcard.max() {
+            return Err(PatternError::InvalidWildcard(format!(
+                "minimum ({}) is larger than maximum ({})",
+                wildcard.min(),
+                wildcard.max()
+            )));
+        }
+        let untouched = wildcard
+            .untouched()
+            .iter()
+            .map(|register| {
+                let register = register.trim();
+                if register.starts_with("$reg:") {
+                    let variable_name = register.trim_start_matches("$reg:");
+                    Ok(UntouchedRegister::Variable(variable_name.to_string()))
+                } else {
+                    Gpr::from_register_name(register)
+                        .map(UntouchedRegister::Fixed)
+                        .ok_or_else(|| {
+                            PatternError::InvalidWildcard(format!("invalid register: {}", register))
+                        })
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(WildcardMatcher {
+            wildcard,
+            untouched,
+        })
+    }
+
+    /// Whether `instruction` doesn't touch any of the fixed untouched registers
+    fn may_skip(&self, instruction: &DecodedInstruction) -> bool {
+        let touched = instruction.touched_registers();
+        self.untouched.iter().all(|untouched| match untouched {
+            UntouchedRegister::Fixed(gpr) => !touched.contains(gpr),
+            UntouchedRegister::Variable(_) => true,
+        })
+    }
+
+    /// Records the constraints and variables resulting from skipping `skipped`
+    fn accept(&self, skipped: &[DecodedInstruction], state: &mut MatchState<'_>) -> bool {
+        let touched: Vec<Gpr> = skipped
+            .iter()
+            .flat_map(|instruction| instruction.touched_registers())
+            .collect();
+        for untouched in &self.untouched {
+            if let UntouchedRegister::Variable(variable_name) = untouched {
+                state
+                    .deferred_untouched
+                    .push((variable_name.clone(), touched.clone()));
+            }
+        }
+
+        if let Some(name) = self.wildcard.name() {
+            // RIP-relative operands would be wrong once the instructions are reassembled somewhere
+            // else
+            if skipped
+                .iter()
+                .any(|instruction| instruction.operands().contains("rip"))
+            {
+                return false;
+            }
+            let instructions = skipped
+                .iter()
+                .map(DecodedInstruction::to_string)
+                .collect::<Vec<_>>()
+                .join("\n");
+            return state
+                .variables
+                .try_add(InstantiatedVariable::new_instructions(
+                    name.to_string(),
+                    instructions,
+                ));
+        }
+        true
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct InstructionPatternMatcher {
     pattern: InstructionPattern,
     regex: String,
+    /// `regex` anchored at the start of the input
+    anchored_regex: Regex,
     /// Mapping from the index of a regex capture group number to either the whole match, a
     /// variable, or a start of a new encoding (which indicates the register variable
     /// instantiations). Note that always only a continous range of capture groups will be captured
@@ -200,14 +910,84 @@ impl InstructionPatternMatcher {
         let encodings = pattern.find_encodings()?;
 
         let (regex, capture_group_purposes) = Self::encodings_to_regex(&encodings);
+        let anchored_regex = Regex::new(&format!("(?s-u)^{}", regex)).unwrap();
 
         Ok(InstructionPatternMatcher {
             pattern,
             regex,
+            anchored_regex,
             capture_group_purposes,
         })
     }
 
+    /// Matches the instruction pattern at `position` and returns the end of the matched
+    /// instruction. Fails if the instruction doesn't match or a variable conflicts with a
+    /// previously instantiated one.
+    fn match_at(
+        &self,
+        bytes: &[u8],
+        position: usize,
+        variables: &mut InstantiatedVariableStore,
+    ) -> Option<usize> {
+        let captures = self.anchored_regex.captures(&bytes[position..])?;
+        // The capture group purposes are relative to the group surrounding `regex`, which is
+        // capture group 1 of `anchored_regex`
+        let capture_group_offset = 1;
+        for i in 1..self.capture_group_purposes.len() {
+            // Iterate through the different encoding capture groups. Exactly one of them
+            // participated in the match.
+            let register_mappings = match self.capture_group_purposes[i] {
+                CaptureGroupPurpose::NewEncoding(ref register_mappings) => register_mappings,
+                _ => continue,
+            };
+            let instruction_match = match captures.get(i + capture_group_offset) {
+                Some(instruction_match) => instruction_match,
+                None => continue,
+            };
+
+            // Found the matched encoding; Extract the number variables of this encoding
+            for j in (i + 1)..self.capture_group_purposes.len() {
+                let variable_name = match self.capture_group_purposes[j] {
+                    CaptureGroupPurpose::NumberVariable(ref variable_name) => variable_name,
+                    _ => break,
+                };
+                let bytes = &captures[j + capture_group_offset];
+                assert!(bytes.len() <= 4);
+                let mut value = 0;
+                for byte in bytes.iter().rev() {
+                    value <<= 8;
+                    value += u64::from(*byte);
+                }
+                if !variables.try_add(InstantiatedVariable::new_number(
+                    variable_name.to_string(),
+                    value,
+                )) {
+                    return None;
+                }
+            }
+            // Also add register variable instantiations
+            for (variable_name, register) in register_mappings {
+                if !variables.try_add(InstantiatedVariable::new_register(
+                    variable_name.to_string(),
+                    *register,
+                )) {
+                    return None;
+                }
+            }
+            // And add length variable instantiations
+            if let Some(length_variable) = self.pattern.length_variable() {
+                if !variables.try_add(InstantiatedVariable::new_length(
+                    length_variable.name().to_string(),
+                    instruction_match.as_bytes().len(),
+                )) {
+                    return None;
+                }
+            }
+            return Some(position + instruction_match.end());
+        }
+        unreachable!()
+    }
+
     fn encodings_to_regex(encodings: &[Encoding]) -> (String, Vec<CaptureGroupPurpose>) {
         let mut capture_group_purposes = Vec::new();
         capture_group_purposes.push(CaptureGroupPurpose::WholeMatch); // group 0 corresponds to the full match
@@ -274,6 +1054,8 @@ pub enum InstantiatedVariable {
     Number(String, u64),
     Register(String, Register),
     Length(String, usize),
+    Instructions(String, String),
+    Label(String, u64),
 }
 
 impl InstantiatedVariable {
@@ -289,11 +1071,21 @@ impl InstantiatedVariable {
         InstantiatedVariable::Length(name, length)
     }
 
+    pub fn new_instructions(name: String, instructions: String) -> InstantiatedVariable {
+        InstantiatedVariable::Instructions(name, instructions)
+    }
+
+    pub fn new_label(name: String, target: u64) -> InstantiatedVariable {
+        InstantiatedVariable::Label(name, target)
+    }
+
     pub fn name(&self) -> &str {
         match self {
             InstantiatedVariable::Number(name, _)
             | InstantiatedVariable::Register(name, _)
-            | InstantiatedVariable::Length(name, _) => name,
+            | InstantiatedVariable::Length(name, _)
+            | InstantiatedVariable::Instructions(name, _)
+            | InstantiatedVariable::Label(name, _) => name,
         }
     }
 
@@ -302,6 +1094,8 @@ impl InstantiatedVariable {
             InstantiatedVariable::Number(..) => VariableType::Number,
             InstantiatedVariable::Register(..) => VariableType::Register,
             InstantiatedVariable::Length(..) => VariableType::Length,
+            InstantiatedVariable::Instructions(..) => VariableType::Instructions,
+            InstantiatedVariable::Label(..) => VariableType::Label,
         }
     }
 
@@ -310,6 +1104,10 @@ impl InstantiatedVariable {
             InstantiatedVariable::Number(name, _) => Variable::new(name, VariableType::Number),
             InstantiatedVariable::Register(name, _) => Variable::new(name, VariableType::Register),
             InstantiatedVariable::Length(name, _) => Variable::new(name, VariableType::Length),
+            InstantiatedVariable::Instructions(name, _) => {
+                Variable::new(name, VariableType::Instructions)
+            }
+            InstantiatedVariable::Label(name, _) => Variable::new(name, VariableType::Label),
         }
     }
 
@@ -318,6 +1116,8 @@ impl InstantiatedVariable {
             InstantiatedVariable::Number(_, number) => format!("0x{:x}", number),
             InstantiatedVariable::Register(_, register) => register.name().to_string(),
             InstantiatedVariable::Length(_, length) => format!("0x{:x}", length),
+            InstantiatedVariable::Instructions(_, instructions) => instructions.clone(),
+            InstantiatedVariable::Label(_, target) => format!("0x{:x}", target),
         }
     }
 }
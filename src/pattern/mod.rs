@@ -1,5 +1,7 @@
+mod filler;
 mod matcher;
 
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 use std::hash::Hash;
 use std::str::FromStr;
@@ -13,6 +15,7 @@ use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_derive::{Deserialize, Serialize};
 
+pub use self::filler::*;
 pub use self::matcher::*;
 use crate::keystone_assemble;
 
@@ -24,32 +27,317 @@ pub enum PatternError {
     DetectionError,
     #[fail(display = "assembly of the pattern failed for all variable instantiations")]
     AssemblyFailed,
+    #[fail(display = "invalid wildcard: {}", _0)]
+    InvalidWildcard(String),
+    #[fail(display = "invalid repetition bounds: {{{},{}}}", _0, _1)]
+    InvalidRepetition(usize, usize),
+    #[fail(display = "invalid label: {}", _0)]
+    InvalidLabel(String),
+    #[fail(display = "the first element of a pattern has to match at least one instruction")]
+    EmptyFirstElement,
+    #[fail(display = "an instruction can't have more than one number variable")]
+    MultipleNumberVariables,
+    #[fail(display = "the pattern can't be compiled into a regex: {}", _0)]
+    RegexFailed(String),
+    #[fail(
+        display = "invalid exclusion (expected `$reg:name != register`): {}",
+        _0
+    )]
+    InvalidExclusion(String),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ObfuscationPattern {
-    pattern: Vec<InstructionPattern>,
+    #[serde(flatten)]
+    metadata: PatternMetadata,
+    pattern: Vec<PatternElement>,
+    /// Blocks which are matched at the target of the branch with the label of the same name
+    /// (`$label:name`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    blocks: BTreeMap<String, Vec<PatternElement>>,
     replacement: Vec<InstructionPattern>,
+    /// Registers (`rax`, `$reg:r1`) and flags (`zf`, `flags` for all) whose value differs after the
+    /// replacement; it's only applied if they're dead after the match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    clobbers: Vec<String>,
+    /// Registers which a register variable may not be (`$reg:r1 != rsp`), e.g. because the
+    /// replacement differs for them; such instantiations don't match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    excluded: Vec<String>,
+    /// Disabled patterns are only used if they're selected explicitly
+    #[serde(default = "enabled_default", skip_serializing_if = "is_enabled")]
+    enabled: bool,
+    /// Overlapping matches of patterns with a higher priority win with `--conflicts priority`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    priority: i32,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_zero(priority: &i32) -> bool {
+    *priority == 0
 }
 
 impl ObfuscationPattern {
     pub fn new(
-        pattern: Vec<InstructionPattern>,
+        pattern: Vec<PatternElement>,
+        replacement: Vec<InstructionPattern>,
+    ) -> ObfuscationPattern {
+        Self::with_blocks(pattern, BTreeMap::new(), replacement)
+    }
+
+    pub fn with_blocks(
+        pattern: Vec<PatternElement>,
+        blocks: BTreeMap<String, Vec<PatternElement>>,
         replacement: Vec<InstructionPattern>,
     ) -> ObfuscationPattern {
         ObfuscationPattern {
+            metadata: PatternMetadata::default(),
             pattern,
+            blocks,
             replacement,
+            clobbers: Vec::new(),
+            excluded: Vec::new(),
+            enabled: true,
+            priority: 0,
         }
     }
 
-    pub fn instruction_patterns(&self) -> &[InstructionPattern] {
+    pub fn metadata(&self) -> &PatternMetadata {
+        &self.metadata
+    }
+
+    pub fn set_metadata(&mut self, metadata: PatternMetadata) {
+        self.metadata = metadata;
+    }
+
+    /// Human-readable reference to the pattern with the (1-based) index `n` in its database for
+    /// logs and reports, e.g. `3 (push-pop: Redundant push and pop)`
+    pub fn label(&self, n: usize) -> String {
+        match (&self.metadata.id, &self.metadata.name) {
+            (Some(id), Some(name)) => format!("{} ({}: {})", n, id, name),
+            (Some(id), None) => format!("{} ({})", n, id),
+            (None, Some(name)) => format!("{} ({})", n, name),
+            (None, None) => n.to_string(),
+        }
+    }
+
+    /// Whether `reference` is the pattern's id, name or (1-based) index `n`
+    pub fn is_referenced_by(&self, reference: &str, n: usize) -> bool {
+        self.metadata.id.as_ref().map(String::as_str) == Some(reference)
+            || self.metadata.name.as_ref().map(String::as_str) == Some(reference)
+            || reference.parse() == Ok(n)
+    }
+
+    pub fn pattern(&self) -> &[PatternElement] {
         &self.pattern
     }
 
+    /// The instructions of the pattern if it's a plain instruction sequence (without wildcards,
+    /// repetitions, alternatives or blocks)
+    pub fn instructions(&self) -> Option<Vec<&InstructionPattern>> {
+        if !self.blocks.is_empty() {
+            return None;
+        }
+        self.pattern
+            .iter()
+            .map(|element| match element {
+                PatternElement::Instruction(instruction) => Some(instruction),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Texts of the instructions of a plain pattern and of its replacement
+    #[cfg(test)]
+    pub(crate) fn texts(&self) -> (Vec<&str>, Vec<&str>) {
+        (
+            self.instructions()
+                .unwrap()
+                .into_iter()
+                .map(InstructionPattern::pattern)
+                .collect(),
+            self.replacement
+                .iter()
+                .map(InstructionPattern::pattern)
+                .collect(),
+        )
+    }
+
+    pub fn blocks(&self) -> &BTreeMap<String, Vec<PatternElement>> {
+        &self.blocks
+    }
+
     pub fn replacement(&self) -> &[InstructionPattern] {
         &self.replacement
     }
+
+    pub fn clobbers(&self) -> &[String] {
+        &self.clobbers
+    }
+
+    pub fn set_clobbers(&mut self, clobbers: Vec<String>) {
+        self.clobbers = clobbers;
+    }
+
+    pub fn excluded(&self) -> &[String] {
+        &self.excluded
+    }
+
+    pub fn set_excluded(&mut self, excluded: Vec<String>) {
+        self.excluded = excluded;
+    }
+
+    /// The excluded instantiations as the names of the register variables and the registers
+    pub fn exclusions(&self) -> Result<Vec<(String, Register)>, PatternError> {
+        self.excluded
+            .iter()
+            .map(|exclusion| {
+                let invalid = || PatternError::InvalidExclusion(exclusion.clone());
+                let mut sides = exclusion.split("!=").map(str::trim);
+                let (variable, register) = match (sides.next(), sides.next(), sides.next()) {
+                    (Some(variable), Some(register), None) => (variable, register),
+                    _ => return Err(invalid()),
+                };
+                let name = match variable.split(':').collect::<Vec<_>>()[..] {
+                    ["$reg", name] if !name.is_empty() => name,
+                    _ => return Err(invalid()),
+                };
+                let register = Register::all()
+                    .iter()
+                    .find(|candidate| candidate.name().eq_ignore_ascii_case(register))
+                    .ok_or_else(invalid)?;
+                Ok((name.to_string(), *register))
+            })
+            .collect()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: i32) {
+        self.priority = priority;
+    }
+}
+
+/// Descriptive information about a pattern which doesn't affect matching or replacement
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatternMetadata {
+    /// Short unique identifier which stays the same when the database is reordered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Where the pattern comes from (e.g. a paper or a sample)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+}
+
+/// An element of an obfuscation pattern. In the pattern database instructions are given as strings
+/// and the other elements as objects.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PatternElement {
+    Instruction(InstructionPattern),
+    /// Consecutive elements; mainly useful as an alternative
+    Sequence(Vec<PatternElement>),
+    /// Matches any of the alternatives
+    AnyOf {
+        any_of: Vec<PatternElement>,
+    },
+    /// Matches `min` to `max` repetitions of the given elements. The variables have to be the same
+    /// in all repetitions.
+    Repeat {
+        repeat: Vec<PatternElement>,
+        #[serde(default = "default_repeat_min")]
+        min: usize,
+        max: usize,
+    },
+    Wildcard {
+        wildcard: Wildcard,
+    },
+}
+
+fn default_repeat_min() -> usize {
+    1
+}
+
+impl From<InstructionPattern> for PatternElement {
+    fn from(instruction_pattern: InstructionPattern) -> PatternElement {
+        PatternElement::Instruction(instruction_pattern)
+    }
+}
+
+impl FromStr for PatternElement {
+    type Err = PatternError;
+    fn from_str(pattern: &str) -> Result<PatternElement, PatternError> {
+        pattern.parse().map(PatternElement::Instruction)
+    }
+}
+
+/// Matches between `min` and `max` arbitrary instructions which don't transfer control and don't
+/// touch any of the `untouched` registers. The matched instructions are available as
+/// `$ins:<name>` in the replacement if the wildcard is named.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Wildcard {
+    #[serde(default)]
+    min: usize,
+    max: usize,
+    /// Register names or register variables (`$reg:name`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    untouched: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+impl Wildcard {
+    pub fn new(min: usize, max: usize, untouched: Vec<String>, name: Option<String>) -> Wildcard {
+        Wildcard {
+            min,
+            max,
+            untouched,
+            name,
+        }
+    }
+
+    pub fn min(&self) -> usize {
+        self.min
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn untouched(&self) -> &[String] {
+        &self.untouched
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(String::as_str)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -144,6 +432,10 @@ pub enum VariableType {
     Number,
     Register,
     Length,
+    /// Instructions matched by a named wildcard; only usable in replacements
+    Instructions,
+    /// Target address of a branch
+    Label,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
@@ -247,7 +539,7 @@ impl InstructionPattern {
                         partial_instance.replace(&variable.to_string(), instantiate_with);
 
                     trace!("instance: {}", instance);
-                    match keystone_assemble(instance) {
+                    match keystone_assemble(instance, 0) {
                         Err(error) => {
                             trace!("assembly failed: {}", error);
                             Err(PatternError::AssemblyFailed)
@@ -293,7 +585,7 @@ impl InstructionPattern {
                 match pattern.number_variables().count() {
                     0 => {
                         trace!("instance: {}", instance);
-                        match keystone_assemble(instance) {
+                        match keystone_assemble(instance, 0) {
                             Err(error) => {
                                 warn!("assembly failed: {}", error);
                                 vec![Err(PatternError::AssemblyFailed)]
@@ -326,7 +618,7 @@ impl InstructionPattern {
                             })
                             .collect()
                     }
-                    _ => unimplemented!("Multiple number variables in an instruction patterns aren't supported yet!"),
+                    _ => unreachable!("find_encodings rejects multiple number variables"),
                 }
             };
 
@@ -362,7 +654,7 @@ impl InstructionPattern {
             }
         }
         match self.variables.len() {
-            0 => match keystone_assemble(self.pattern.to_string()).map(|asm| asm.bytes) {
+            0 => match keystone_assemble(self.pattern.to_string(), 0).map(|asm| asm.bytes) {
                 Ok(asm) => {
                     let mut parts = Vec::new();
                     parts.push(EncodingPart::Fixed(asm));
@@ -375,9 +667,7 @@ impl InstructionPattern {
             _ if self.number_variables().count() <= 1 => {
                 pattern_to_encodings(self).map(|set| set.into_iter().collect())
             }
-            _ => unimplemented!(
-                "Multiple number variables in an instruction patterns aren't supported yet!"
-            ),
+            _ => Err(PatternError::MultipleNumberVariables),
         }
     }
 }
@@ -398,6 +688,8 @@ impl FromStr for InstructionPattern {
                 "num" => VariableType::Number,
                 "reg" => VariableType::Register,
                 "len" => VariableType::Length,
+                "ins" => VariableType::Instructions,
+                "label" => VariableType::Label,
                 typee => return Err(PatternError::InvalidVariableType(typee.to_string())),
             };
             let var = Variable { typee, name };
@@ -457,6 +749,8 @@ impl Display for Variable {
                 VariableType::Number => "num",
                 VariableType::Register => "reg",
                 VariableType::Length => "len",
+                VariableType::Instructions => "ins",
+                VariableType::Label => "label",
             },
             self.name
         )
@@ -1,14 +1,235 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use fxhash::FxHashSet;
+use serde_json::Value;
+
 use crate::pattern::ObfuscationPattern;
+use crate::pattern_dsl;
 
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PatternDatabase(Vec<ObfuscationPattern>);
 
+/// A database file which builds on other files. Included files are layered in order before the
+/// file's own patterns; `disable` refers to the ids of patterns of any of these layers.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayeredDatabase {
+    /// Paths relative to the including file
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    patterns: Vec<ObfuscationPattern>,
+    #[serde(default)]
+    disable: Vec<String>,
+}
+
 impl PatternDatabase {
+    pub fn new(patterns: Vec<ObfuscationPattern>) -> PatternDatabase {
+        PatternDatabase(patterns)
+    }
+
+    /// Loads a database file which is either in the text format (with the extension `.pbd`), a
+    /// list of patterns or an object with `include`, `patterns` and `disable` lists
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<PatternDatabase, Box<dyn Error>> {
+        let mut database = PatternDatabase(Vec::new());
+        database.load_layer(path.as_ref(), &mut Vec::new())?;
+        Ok(database)
+    }
+
+    /// Loads the database files in order; each one is layered on top of the previous ones
+    pub fn load_all<P: AsRef<Path>>(paths: &[P]) -> Result<PatternDatabase, Box<dyn Error>> {
+        let mut database = PatternDatabase(Vec::new());
+        for path in paths {
+            database.load_layer(path.as_ref(), &mut Vec::new())?;
+        }
+        Ok(database)
+    }
+
+    /// `including` are the files which (transitively) include `path`
+    fn load_layer(
+        &mut self,
+        path: &Path,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<(), Box<dyn Error>> {
+        let canonical = path
+            .canonicalize()
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        if including.contains(&canonical) {
+            return Err(format!("{} includes itself", path.display()).into());
+        }
+        if path.extension() == Some(OsStr::new("pbd")) {
+            let text = fs::read_to_string(path)?;
+            let database = pattern_dsl::parse(&text)
+                .map_err(|error| format!("{}: {}", path.display(), error))?;
+            self.layer(database.0);
+            return Ok(());
+        }
+        let value: Value = serde_json::from_reader(File::open(path)?)
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        let layer = match value {
+            Value::Array(_) => LayeredDatabase {
+                patterns: serde_json::from_value(value)?,
+                ..LayeredDatabase::default()
+            },
+            _ => serde_json::from_value(value)
+                .map_err(|error| format!("{}: {}", path.display(), error))?,
+        };
+
+        if let Some(id) = PatternDatabase(layer.patterns.clone()).duplicate_id() {
+            return Err(format!(
+                "{}: pattern id {} is used more than once",
+                path.display(),
+                id
+            )
+            .into());
+        }
+
+        including.push(canonical);
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        for include in &layer.include {
+            self.load_layer(&directory.join(include), including)?;
+        }
+        including.pop();
+
+        self.layer(layer.patterns);
+        for id in &layer.disable {
+            if !self.disable(id) {
+                return Err(
+                    format!("{}: can't disable unknown pattern {}", path.display(), id).into(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the patterns on top of the database: a pattern with the id of an existing one replaces
+    /// it (at its position), the others are appended
+    pub fn layer(&mut self, patterns: Vec<ObfuscationPattern>) {
+        for pattern in patterns {
+            let existing = pattern.metadata().id.as_ref().and_then(|id| {
+                self.0
+                    .iter()
+                    .position(|other| other.metadata().id.as_ref() == Some(id))
+            });
+            match existing {
+                Some(index) => self.0[index] = pattern,
+                None => self.0.push(pattern),
+            }
+        }
+    }
+
+    /// Disables the pattern with the id; returns whether there is one
+    pub fn disable(&mut self, id: &str) -> bool {
+        match self
+            .0
+            .iter_mut()
+            .find(|pattern| pattern.metadata().id.as_ref().map(String::as_str) == Some(id))
+        {
+            Some(pattern) => {
+                pattern.set_enabled(false);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn patterns(&self) -> &[ObfuscationPattern] {
         &self.0
     }
+
+    /// Returns the first pattern id which is used more than once
+    pub fn duplicate_id(&self) -> Option<&str> {
+        let mut ids = FxHashSet::default();
+        self.0
+            .iter()
+            .filter_map(|pattern| pattern.metadata().id.as_ref())
+            .find(|id| !ids.insert(*id))
+            .map(String::as_str)
+    }
+
+    /// Patterns selected by `filter` with their (1-based) index in the database
+    pub fn select<'a>(
+        &'a self,
+        filter: &'a PatternFilter,
+    ) -> impl Iterator<Item = (usize, &'a ObfuscationPattern)> + 'a {
+        self.0
+            .iter()
+            .enumerate()
+            .map(|(i, pattern)| (i + 1, pattern))
+            .filter(move |&(n, pattern)| filter.selects(pattern, n))
+    }
+}
+
+/// Refers to patterns of a database; parsed from `tag:<tag>`, a (1-based) index or an id or name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternSelector {
+    Tag(String),
+    /// Index, id or name
+    Pattern(String),
+}
+
+impl PatternSelector {
+    pub fn matches(&self, pattern: &ObfuscationPattern, n: usize) -> bool {
+        match self {
+            PatternSelector::Tag(tag) => pattern.metadata().tags.contains(tag),
+            PatternSelector::Pattern(reference) => pattern.is_referenced_by(reference, n),
+        }
+    }
+}
+
+impl FromStr for PatternSelector {
+    type Err = String;
+
+    fn from_str(selector: &str) -> Result<PatternSelector, String> {
+        let selector = selector.trim();
+        if selector.starts_with("tag:") {
+            let tag = &selector[4..];
+            if tag.is_empty() {
+                return Err("empty tag".to_string());
+            }
+            Ok(PatternSelector::Tag(tag.to_string()))
+        } else if selector.is_empty() {
+            Err("empty pattern selector".to_string())
+        } else {
+            Ok(PatternSelector::Pattern(selector.to_string()))
+        }
+    }
+}
+
+/// Selects the enabled patterns which match any of `include` (or all if it's empty) and none of
+/// `exclude`. Disabled patterns are only selected if they're included by their index, id or name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatternFilter {
+    pub include: Vec<PatternSelector>,
+    pub exclude: Vec<PatternSelector>,
+}
+
+impl PatternFilter {
+    pub fn selects(&self, pattern: &ObfuscationPattern, n: usize) -> bool {
+        let included = if pattern.is_enabled() {
+            self.include.is_empty()
+                || self
+                    .include
+                    .iter()
+                    .any(|selector| selector.matches(pattern, n))
+        } else {
+            self.include.iter().any(|selector| match selector {
+                PatternSelector::Pattern(_) => selector.matches(pattern, n),
+                PatternSelector::Tag(_) => false,
+            })
+        };
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|selector| selector.matches(pattern, n))
+    }
 }
 
 #[cfg(test)]
@@ -34,4 +255,131 @@ mod tests {
             pattern_database
         );
     }
+
+    #[test]
+    fn pattern_metadata() {
+        let pattern_database: PatternDatabase = serde_json::from_str(
+            r#"[
+                {
+                    "id": "push-pop",
+                    "name": "Redundant push and pop",
+                    "tags": ["stack", "nop"],
+                    "pattern": ["push $reg:r1", "pop $reg:r1"],
+                    "replacement": []
+                },
+                { "pattern": ["nop"], "replacement": [] }
+            ]"#,
+        )
+        .unwrap();
+        let patterns = pattern_database.patterns();
+        assert_eq!(patterns[0].metadata().tags, vec!["stack", "nop"]);
+        assert_eq!(patterns[0].label(1), "1 (push-pop: Redundant push and pop)");
+        assert_eq!(patterns[1].label(2), "2");
+        assert!(patterns[0].is_referenced_by("push-pop", 1));
+        assert!(patterns[1].is_referenced_by("2", 2));
+        assert!(!patterns[1].is_referenced_by("push-pop", 2));
+        let selector = |selector: &str| selector.parse::<PatternSelector>().unwrap();
+        assert!(selector("tag:stack").matches(&patterns[0], 1));
+        assert!(!selector("tag:stack").matches(&patterns[1], 2));
+        assert!(selector("push-pop").matches(&patterns[0], 1));
+        assert_eq!(pattern_database.duplicate_id(), None);
+
+        // Unset metadata isn't serialized
+        let serialized = serde_json::to_string(&pattern_database).unwrap();
+        assert!(!serialized.contains("description"));
+        assert_eq!(
+            serde_json::from_str::<PatternDatabase>(&serialized).unwrap(),
+            pattern_database
+        );
+
+        let mut duplicated = patterns.to_vec();
+        duplicated.push(patterns[0].clone());
+        assert_eq!(PatternDatabase(duplicated).duplicate_id(), Some("push-pop"));
+    }
+
+    #[test]
+    fn pattern_selection() {
+        let pattern_database: PatternDatabase = serde_json::from_str(
+            r#"[
+                { "id": "a", "tags": ["stack"], "pattern": ["nop"], "replacement": [] },
+                { "id": "b", "tags": ["stack"], "pattern": ["nop"], "replacement": [] },
+                { "id": "c", "pattern": ["nop"], "replacement": [], "enabled": false },
+                { "name": "d", "pattern": ["nop"], "replacement": [] }
+            ]"#,
+        )
+        .unwrap();
+        let selected = |include: &[&str], exclude: &[&str]| -> Vec<usize> {
+            let filter = PatternFilter {
+                include: include.iter().map(|s| s.parse().unwrap()).collect(),
+                exclude: exclude.iter().map(|s| s.parse().unwrap()).collect(),
+            };
+            pattern_database.select(&filter).map(|(n, _)| n).collect()
+        };
+
+        assert_eq!(selected(&[], &[]), vec![1, 2, 4]);
+        assert_eq!(selected(&["tag:stack"], &["b"]), vec![1]);
+        assert_eq!(selected(&["c", "d"], &[]), vec![3, 4]);
+        assert_eq!(selected(&["3"], &[]), vec![3]);
+        assert_eq!(selected(&[], &["tag:stack", "4"]), Vec::<usize>::new());
+        assert!("tag:".parse::<PatternSelector>().is_err());
+    }
+
+    #[test]
+    fn layered_databases() {
+        let directory = tempfile::tempdir().unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = directory.path().join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+        let base = write(
+            "base.json",
+            r#"[
+                { "id": "a", "pattern": ["nop"], "replacement": [] },
+                { "id": "b", "pattern": ["nop"], "replacement": [] },
+                { "pattern": ["int3"], "replacement": [] }
+            ]"#,
+        );
+        let sample = write(
+            "sample.json",
+            r#"{
+                "include": ["base.json"],
+                "patterns": [
+                    { "id": "b", "pattern": ["ret"], "replacement": [] },
+                    { "id": "c", "pattern": ["nop"], "replacement": [] }
+                ],
+                "disable": ["a"]
+            }"#,
+        );
+        let extra = write(
+            "extra.json",
+            r#"[{ "id": "c", "name": "overridden", "pattern": ["nop"], "replacement": [] }]"#,
+        );
+
+        let database = PatternDatabase::load_all(&[&sample, &extra]).unwrap();
+        let patterns = database.patterns();
+        let ids: Vec<_> = patterns
+            .iter()
+            .map(|pattern| pattern.metadata().id.clone())
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                Some("a".to_string()),
+                Some("b".to_string()),
+                None,
+                Some("c".to_string())
+            ]
+        );
+        assert!(!patterns[0].is_enabled());
+        assert!(patterns[1].is_enabled());
+        assert_eq!(patterns[1].pattern(), &["ret".parse().unwrap()][..]);
+        assert_eq!(patterns[3].metadata().name, Some("overridden".to_string()));
+        assert_eq!(PatternDatabase::load(&base).unwrap().patterns().len(), 3);
+
+        let cycle = write("cycle.json", r#"{ "include": ["cycle.json"] }"#);
+        assert!(PatternDatabase::load(&cycle).is_err());
+        let unknown = write("unknown.json", r#"{ "disable": ["a"] }"#);
+        assert!(PatternDatabase::load(&unknown).is_err());
+    }
 }
@@ -0,0 +1,457 @@
+//! Text format for pattern databases. Patterns are separated by blank lines; each one consists of
+//! optional metadata lines, the pattern's instructions, its blocks and its replacement:
+//!
+//! ```text
+//! # Comment
+//! @id cmp-jz
+//! @name Opaque predicate
+//! @tags control-flow, junk
+//! @clobbers flags
+//! cmp $reg:r1, $reg:r1
+//! jz $label:taken
+//! taken:
+//! jmp $label:real
+//! =>
+//! jmp $label:real
+//! ```
+//!
+//! Metadata keys are `id`, `name`, `description` (repeated lines are joined with newlines),
+//! `source`, `author`, `tags`, `clobbers` and `excluded` (all comma-separated), `priority` (an
+//! integer) and `disabled` (without a value). Values may contain the escapes `\n` (newline), `\\` and, in lists,
+//! `\,`; a key without a value sets the field to an empty string. A line `name:` starts the block
+//! `name`. Wildcards, alternatives and repetitions are written as single-line JSON objects like in
+//! the JSON database (e.g. `{ "wildcard": { "max": 2 } }`). Text after `=>` on the same line is the
+//! first instruction of the replacement. Lines starting with `#` are comments.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use failure::Fail;
+
+use crate::pattern::*;
+use crate::pattern_database::PatternDatabase;
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+#[fail(display = "line {}, column {}: {}", line, column, message)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// Pattern which is being parsed
+#[derive(Default)]
+struct Entry {
+    metadata: PatternMetadata,
+    clobbers: Vec<String>,
+    excluded: Vec<String>,
+    priority: Option<i32>,
+    disabled: bool,
+    pattern: Vec<PatternElement>,
+    blocks: BTreeMap<String, Vec<PatternElement>>,
+    /// Block to which the following elements belong
+    block: Option<String>,
+    replacement: Option<Vec<InstructionPattern>>,
+    /// Line on which the pattern starts
+    start_line: usize,
+}
+
+impl Entry {
+    fn finish(self, line: usize) -> Result<ObfuscationPattern, ParseError> {
+        let replacement = self.replacement.ok_or_else(|| ParseError {
+            line,
+            column: 1,
+            message: "expected `=>` and the replacement".to_string(),
+        })?;
+        let mut pattern = ObfuscationPattern::with_blocks(self.pattern, self.blocks, replacement);
+        pattern.set_metadata(self.metadata);
+        pattern.set_clobbers(self.clobbers);
+        pattern.set_excluded(self.excluded);
+        pattern.set_priority(self.priority.unwrap_or(0));
+        pattern.set_enabled(!self.disabled);
+        Ok(pattern)
+    }
+}
+
+/// Parses a pattern database in the text format
+pub fn parse(text: &str) -> Result<PatternDatabase, ParseError> {
+    let mut patterns = Vec::new();
+    let mut entry = Entry::default();
+    let mut line_n = 0;
+    for (i, raw_line) in text.lines().enumerate() {
+        line_n = i + 1;
+        let indentation = raw_line.len() - raw_line.trim_start().len();
+        let line = raw_line.trim();
+        let error = |column: usize, message: String| ParseError {
+            line: line_n,
+            column: indentation + column,
+            message,
+        };
+
+        if line.is_empty() {
+            if entry.start_line != 0 {
+                push_entry(&mut patterns, entry, line_n)?;
+                entry = Entry::default();
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        if entry.start_line == 0 {
+            entry.start_line = line_n;
+        }
+
+        if let Some(replacement) = &mut entry.replacement {
+            if line.starts_with("=>") || line.starts_with('@') {
+                return Err(error(
+                    1,
+                    "expected an instruction or a blank line".to_string(),
+                ));
+            }
+            replacement
+                .push(parse_instruction(line).map_err(|(column, message)| error(column, message))?);
+        } else if line.starts_with("=>") {
+            if entry.pattern.is_empty() {
+                return Err(error(1, "the pattern has no instructions".to_string()));
+            }
+            let rest = &line[2..];
+            let mut replacement = Vec::new();
+            if !rest.trim().is_empty() {
+                let offset = 2 + rest.len() - rest.trim_start().len();
+                replacement.push(
+                    parse_instruction(rest.trim())
+                        .map_err(|(column, message)| error(offset + column, message))?,
+                );
+            }
+            entry.replacement = Some(replacement);
+        } else if line.starts_with('@') {
+            if !entry.pattern.is_empty() {
+                return Err(error(
+                    1,
+                    "metadata has to come before the pattern".to_string(),
+                ));
+            }
+            parse_metadata(&line[1..], &mut entry).map_err(|message| error(2, message))?;
+        } else if line.ends_with(':') && is_identifier(&line[..line.len() - 1]) {
+            let name = line[..line.len() - 1].to_string();
+            if entry.pattern.is_empty() {
+                return Err(error(1, "a block has to follow the pattern".to_string()));
+            }
+            if entry.blocks.contains_key(&name) {
+                return Err(error(1, format!("duplicate block {}", name)));
+            }
+            entry.blocks.insert(name.clone(), Vec::new());
+            entry.block = Some(name);
+        } else {
+            let element = if line.starts_with('{') || line.starts_with('[') {
+                serde_json::from_str(line).map_err(|json_error| {
+                    error(json_error.column().max(1), json_error.to_string())
+                })?
+            } else {
+                PatternElement::Instruction(
+                    parse_instruction(line).map_err(|(column, message)| error(column, message))?,
+                )
+            };
+            match &entry.block {
+                Some(name) => entry.blocks.get_mut(name).unwrap().push(element),
+                None => entry.pattern.push(element),
+            }
+        }
+    }
+    if entry.start_line != 0 {
+        push_entry(&mut patterns, entry, line_n)?;
+    }
+    Ok(PatternDatabase::new(patterns))
+}
+
+/// Adds the pattern which ends at `line` (exclusive)
+fn push_entry(
+    patterns: &mut Vec<ObfuscationPattern>,
+    entry: Entry,
+    line: usize,
+) -> Result<(), ParseError> {
+    let start_line = entry.start_line;
+    let pattern = entry.finish(line)?;
+    if let Some(id) = &pattern.metadata().id {
+        if patterns
+            .iter()
+            .any(|other| other.metadata().id.as_ref() == Some(id))
+        {
+            return Err(ParseError {
+                line: start_line,
+                column: 1,
+                message: format!("pattern id {} is used more than once", id),
+            });
+        }
+    }
+    patterns.push(pattern);
+    Ok(())
+}
+
+/// Returns the (1-based) column and message on error
+fn parse_instruction(text: &str) -> Result<InstructionPattern, (usize, String)> {
+    text.parse().map_err(|error| {
+        let column = match &error {
+            PatternError::InvalidVariableType(typee) => text
+                .find(&format!("${}:", typee))
+                .map_or(1, |index| index + 1),
+            _ => 1,
+        };
+        (column, error.to_string())
+    })
+}
+
+fn parse_metadata(line: &str, entry: &mut Entry) -> Result<(), String> {
+    let (key, value) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+    let list = || -> Result<Vec<String>, String> {
+        let mut items = Vec::new();
+        let mut item = String::new();
+        let mut escaped = false;
+        for c in value.chars() {
+            match c {
+                ',' if !escaped => {
+                    items.push(item.clone());
+                    item.clear();
+                }
+                c => {
+                    escaped = c == '\\' && !escaped;
+                    item.push(c);
+                }
+            }
+        }
+        items.push(item);
+        let mut list = Vec::new();
+        for item in items {
+            let item = item.trim();
+            if !item.is_empty() {
+                list.push(unescape(item)?);
+            }
+        }
+        Ok(list)
+    };
+    let metadata = &mut entry.metadata;
+    let single = |field: &mut Option<String>| {
+        if field.is_some() {
+            return Err(format!("duplicate @{}", key));
+        }
+        *field = Some(unescape(value)?);
+        Ok(())
+    };
+    match key {
+        "id" => single(&mut metadata.id)?,
+        "name" => single(&mut metadata.name)?,
+        "source" => single(&mut metadata.source)?,
+        "author" => single(&mut metadata.author)?,
+        "description" => match &mut metadata.description {
+            Some(description) => {
+                description.push('\n');
+                description.push_str(&unescape(value)?);
+            }
+            None => metadata.description = Some(unescape(value)?),
+        },
+        "tags" => metadata.tags.extend(list()?),
+        "clobbers" => entry.clobbers.extend(list()?),
+        "excluded" => entry.excluded.extend(list()?),
+        "priority" if entry.priority.is_some() => return Err("duplicate @priority".to_string()),
+        "priority" => {
+            entry.priority = Some(
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid priority: {}", value))?,
+            )
+        }
+        "disabled" if value.is_empty() => entry.disabled = true,
+        "disabled" => return Err("@disabled doesn't take a value".to_string()),
+        _ => return Err(format!("unknown metadata @{}", key)),
+    }
+    Ok(())
+}
+
+/// Resolves the escapes `\n`, `\\` and `\,`
+fn unescape(value: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c @ '\\') | Some(c @ ',') => unescaped.push(c),
+            Some(c) => return Err(format!("invalid escape \\{}", c)),
+            None => return Err("incomplete escape at the end of the value".to_string()),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// Escapes backslashes, newlines and, if the value is an item of a list, commas
+fn escape(value: &str, list_item: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            ',' if list_item => escaped.push_str("\\,"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Formats the database in the text format; parsing the result yields the same database
+pub fn format(database: &PatternDatabase) -> String {
+    let mut text = String::new();
+    for (i, pattern) in database.patterns().iter().enumerate() {
+        if i > 0 {
+            text.push('\n');
+        }
+        let metadata = pattern.metadata();
+        let fields = [
+            ("id", &metadata.id),
+            ("name", &metadata.name),
+            ("description", &metadata.description),
+            ("source", &metadata.source),
+            ("author", &metadata.author),
+        ];
+        for (key, value) in fields.iter() {
+            match value {
+                Some(value) if value.is_empty() => writeln!(text, "@{}", key).unwrap(),
+                Some(value) => writeln!(text, "@{} {}", key, escape(value, false)).unwrap(),
+                None => {}
+            }
+        }
+        let list = |items: &[String]| {
+            items
+                .iter()
+                .map(|item| escape(item, true))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if !metadata.tags.is_empty() {
+            writeln!(text, "@tags {}", list(&metadata.tags)).unwrap();
+        }
+        if !pattern.clobbers().is_empty() {
+            writeln!(text, "@clobbers {}", list(pattern.clobbers())).unwrap();
+        }
+        if !pattern.excluded().is_empty() {
+            writeln!(text, "@excluded {}", list(pattern.excluded())).unwrap();
+        }
+        if pattern.priority() != 0 {
+            writeln!(text, "@priority {}", pattern.priority()).unwrap();
+        }
+        if !pattern.is_enabled() {
+            text.push_str("@disabled\n");
+        }
+        format_elements(&mut text, pattern.pattern());
+        for (name, block) in pattern.blocks() {
+            writeln!(text, "{}:", name).unwrap();
+            format_elements(&mut text, block);
+        }
+        text.push_str("=>\n");
+        for instruction in pattern.replacement() {
+            writeln!(text, "{}", instruction.pattern()).unwrap();
+        }
+    }
+    text
+}
+
+fn format_elements(text: &mut String, elements: &[PatternElement]) {
+    for element in elements {
+        match element {
+            PatternElement::Instruction(instruction) => {
+                writeln!(text, "{}", instruction.pattern()).unwrap()
+            }
+            element => writeln!(text, "{}", serde_json::to_string(element).unwrap()).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        let text = "\
+# Stack juggling
+@id push-pop
+@name Redundant push and pop
+@author Someone\\nSomeone else
+@description Pushes a register
+@description and pops it again
+@source
+@tags stack, junk, a\\, b\\\\
+@excluded $reg:r1 != rsp, $reg:r1 != esp
+push $reg:r1
+{ \"wildcard\": { \"max\": 2, \"untouched\": [\"$reg:r1\"] } }
+pop $reg:r1
+=>
+
+@clobbers flags
+@priority -2
+@disabled
+  cmp $reg:r1, $reg:r1
+  jz $label:taken
+taken:
+  jmp $label:real
+=> jmp $label:real
+";
+        let database = parse(text).unwrap();
+        let patterns = database.patterns();
+        assert_eq!(patterns.len(), 2);
+        assert_eq!(
+            patterns[0].metadata().description,
+            Some("Pushes a register\nand pops it again".to_string())
+        );
+        assert_eq!(
+            patterns[0].metadata().author,
+            Some("Someone\nSomeone else".to_string())
+        );
+        assert_eq!(patterns[0].metadata().source, Some(String::new()));
+        assert_eq!(patterns[0].metadata().tags, vec!["stack", "junk", "a, b\\"]);
+        assert_eq!(
+            patterns[0].exclusions(),
+            Ok(vec![
+                ("r1".to_string(), Register::RSP),
+                ("r1".to_string(), Register::ESP)
+            ])
+        );
+        assert_eq!(patterns[0].pattern().len(), 3);
+        assert!(patterns[0].replacement().is_empty());
+        assert_eq!(patterns[1].clobbers(), &["flags".to_string()][..]);
+        assert!(!patterns[1].is_enabled());
+        assert_eq!(patterns[1].priority(), -2);
+        assert_eq!(patterns[1].blocks()["taken"].len(), 1);
+        assert_eq!(patterns[1].replacement()[0].pattern(), "jmp $label:real");
+
+        let formatted = format(&database);
+        assert_eq!(parse(&formatted).unwrap(), database);
+        assert_eq!(format(&parse(&formatted).unwrap()), formatted);
+    }
+
+    #[test]
+    fn error_positions() {
+        let error = |text: &str| {
+            let error = parse(text).unwrap_err();
+            (error.line, error.column)
+        };
+        assert_eq!(error("push rax\n=>\n  mov $foo:x, rax\n"), (3, 7));
+        assert_eq!(error("push rax\n=> pop $foo:x\n"), (2, 8));
+        assert_eq!(error("push rax\npop rax\n"), (2, 1));
+        assert_eq!(error("@id a\n@unknown\n"), (2, 2));
+        assert_eq!(error("=>\n"), (1, 1));
+        assert_eq!(error("@id a\nnop\n=>\n\n@id a\nnop\n=>\n"), (5, 1));
+        assert_eq!(error("@name a\\b\n"), (1, 2));
+        assert_eq!(error("push rax\n{ \"wildcard\": 1 }\n=>\n").0, 2);
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::BTreeSet;
+use std::fmt::{self, Display};
+use std::ops::AddAssign;
+
+use fxhash::FxHashSet;
+use lazy_static::lazy_static;
+use log::*;
+use regex::Regex;
+
+use crate::binary::Span;
+use crate::code_cave::{CodeCaveError, CodeCaves, Trampoline};
+use crate::disassembly::Disassembler;
+use crate::liveness;
+use crate::nasm_assemble;
+use crate::pattern::*;
+use crate::x86::{Gpr, Location};
+
+/// What happened to a single match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplacementOutcome {
+    Applied,
+    /// The matched code jumps to the replacement in a code cave
+    AppliedInCodeCave(Trampoline),
+    SkippedTooLarge {
+        replacement: usize,
+        available: usize,
+    },
+    AssemblyFailed,
+    ConstraintFailed(String),
+    /// The matched code contains a known branch target after its first instruction
+    Unsafe {
+        branch_target: u64,
+    },
+    /// The match overlaps a match of the same scan which takes precedence
+    Conflicting,
+}
+
+impl ReplacementOutcome {
+    pub fn is_applied(&self) -> bool {
+        match self {
+            ReplacementOutcome::Applied | ReplacementOutcome::AppliedInCodeCave(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Display for ReplacementOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplacementOutcome::Applied => write!(f, "applied"),
+            ReplacementOutcome::AppliedInCodeCave(trampoline) => write!(
+                f,
+                "applied in code cave at 0x{:x} - 0x{:x}",
+                trampoline.cave.start, trampoline.cave.end
+            ),
+            ReplacementOutcome::SkippedTooLarge {
+                replacement,
+                available,
+            } => write!(
+                f,
+                "skipped as the replacement is too large ({} > {} bytes)",
+                replacement, available
+            ),
+            ReplacementOutcome::AssemblyFailed => write!(f, "assembly of the replacement failed"),
+            ReplacementOutcome::ConstraintFailed(reason) => {
+                write!(f, "constraint failed: {}", reason)
+            }
+            ReplacementOutcome::Unsafe { branch_target } => write!(
+                f,
+                "skipped as the branch target 0x{:x} is inside of the match",
+                branch_target
+            ),
+            ReplacementOutcome::Conflicting => {
+                write!(f, "skipped as it overlaps a match which takes precedence")
+            }
+        }
+    }
+}
+
+/// Number of matches per outcome
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplacementStatistics {
+    pub applied: usize,
+    pub skipped_too_large: usize,
+    pub assembly_failed: usize,
+    pub constraint_failed: usize,
+    pub skipped_unsafe: usize,
+    pub skipped_conflicting: usize,
+}
+
+impl ReplacementStatistics {
+    pub fn record(&mut self, outcome: &ReplacementOutcome) {
+        match outcome {
+            ReplacementOutcome::Applied | ReplacementOutcome::AppliedInCodeCave(_) => {
+                self.applied += 1
+            }
+            ReplacementOutcome::SkippedTooLarge { .. } => self.skipped_too_large += 1,
+            ReplacementOutcome::AssemblyFailed => self.assembly_failed += 1,
+            ReplacementOutcome::ConstraintFailed(_) => self.constraint_failed += 1,
+            ReplacementOutcome::Unsafe { .. } => self.skipped_unsafe += 1,
+            ReplacementOutcome::Conflicting => self.skipped_conflicting += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.applied
+            + self.skipped_too_large
+            + self.assembly_failed
+            + self.constraint_failed
+            + self.skipped_unsafe
+            + self.skipped_conflicting
+    }
+}
+
+impl AddAssign for ReplacementStatistics {
+    fn add_assign(&mut self, other: ReplacementStatistics) {
+        self.applied += other.applied;
+        self.skipped_too_large += other.skipped_too_large;
+        self.assembly_failed += other.assembly_failed;
+        self.constraint_failed += other.constraint_failed;
+        self.skipped_unsafe += other.skipped_unsafe;
+        self.skipped_conflicting += other.skipped_conflicting;
+    }
+}
+
+impl Display for ReplacementStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} applied, {} too large, {} failed to assemble, {} failed constraints, {} unsafe, {} \
+             conflicting",
+            self.applied,
+            self.skipped_too_large,
+            self.assembly_failed,
+            self.constraint_failed,
+            self.skipped_unsafe,
+            self.skipped_conflicting
+        )
+    }
+}
+
+/// Substitutes the instantiated variables into the replacement of `pattern`. Fails if the
+/// replacement uses a variable which wasn't instantiated by the match.
+pub fn instantiate_replacement(
+    pattern: &ObfuscationPattern,
+    variables: &[InstantiatedVariable],
+) -> Result<String, String> {
+    lazy_static! {
+        static ref VARIABLE: Regex = Regex::new(r"\$\w+:\w+").unwrap();
+    }
+
+    let mut replacement_asm = pattern
+        .replacement()
+        .iter()
+        .map(InstructionPattern::pattern)
+        .collect::<Vec<_>>()
+        .join("\n");
+    for instantiated_variable in variables {
+        let variable = instantiated_variable.as_variable();
+        let value = instantiated_variable.value();
+        replacement_asm = replacement_asm.replace(&variable.to_string(), &value);
+    }
+    match VARIABLE.find(&replacement_asm) {
+        Some(unbound) => Err(format!("unbound variable {}", unbound.as_str())),
+        None => Ok(replacement_asm),
+    }
+}
+
+/// Resolves the clobbered registers and flags of `pattern`. Fails if a name is invalid or refers to
+/// a register variable which wasn't instantiated by the match.
+pub fn clobbered_locations(
+    pattern: &ObfuscationPattern,
+    variables: &[InstantiatedVariable],
+) -> Result<FxHashSet<Location>, String> {
+    let mut locations = FxHashSet::default();
+    for clobber in pattern.clobbers() {
+        let resolved = if clobber.starts_with("$reg:") {
+            variables
+                .iter()
+                .find_map(|variable| match variable {
+                    InstantiatedVariable::Register(name, register) if name == &clobber[5..] => {
+                        Gpr::from_register_name(register.name())
+                            .map(|gpr| vec![Location::Register(gpr)])
+                    }
+                    _ => None,
+                })
+                .ok_or_else(|| format!("unbound variable {}", clobber))?
+        } else {
+            Location::from_name(clobber).ok_or_else(|| format!("invalid clobber {}", clobber))?
+        };
+        locations.extend(resolved);
+    }
+    Ok(locations)
+}
+
+/// Applies replacements to the code spans
+#[derive(Debug, Clone)]
+pub struct Rewriter {
+    filler_set: FillerSet,
+    /// Caves for replacements which are larger than the matched code; `None` if they're skipped
+    code_caves: Option<CodeCaves>,
+    /// Addresses which may be reached from elsewhere; the code in between two instructions of a
+    /// match must not be one of them
+    branch_targets: BTreeSet<u64>,
+}
+
+impl Rewriter {
+    pub fn new(filler_set: FillerSet, code_caves: Option<CodeCaves>) -> Rewriter {
+        Rewriter {
+            filler_set,
+            code_caves,
+            branch_targets: BTreeSet::new(),
+        }
+    }
+
+    pub fn branch_targets(&self) -> &BTreeSet<u64> {
+        &self.branch_targets
+    }
+
+    /// Replaces the known branch targets; they need to be updated when the code changes
+    pub fn set_branch_targets(&mut self, branch_targets: BTreeSet<u64>) {
+        self.branch_targets = branch_targets;
+    }
+
+    pub fn code_caves(&self) -> Option<&CodeCaves> {
+        self.code_caves.as_ref()
+    }
+
+    /// Replaces the code matched by `pattern_match` in the span with index `span_index`
+    pub fn apply(
+        &mut self,
+        spans: &mut [Span],
+        span_index: usize,
+        pattern: &ObfuscationPattern,
+        pattern_match: &PatternMatch,
+    ) -> ReplacementOutcome {
+        // Replacing the code would break every branch into the middle of it
+        let match_start = (spans[span_index].vaddr + pattern_match.start()) as u64;
+        let match_end = (spans[span_index].vaddr + pattern_match.end()) as u64;
+        if let Some(branch_target) = self.branch_targets.range(match_start + 1..match_end).next() {
+            return ReplacementOutcome::Unsafe {
+                branch_target: *branch_target,
+            };
+        }
+
+        let clobbered = match clobbered_locations(pattern, pattern_match.variables()) {
+            Ok(clobbered) => clobbered,
+            Err(reason) => return ReplacementOutcome::ConstraintFailed(reason),
+        };
+        if !clobbered.is_empty()
+            && !liveness::is_dead(spans, match_end, &clobbered, &Disassembler::new())
+        {
+            let mut clobbered: Vec<_> = clobbered.iter().map(Location::to_string).collect();
+            clobbered.sort();
+            return ReplacementOutcome::ConstraintFailed(format!(
+                "clobbered {} may be live",
+                clobbered.join(", ")
+            ));
+        }
+
+        let replacement_asm = match instantiate_replacement(pattern, pattern_match.variables()) {
+            Ok(replacement_asm) => replacement_asm,
+            Err(reason) => return ReplacementOutcome::ConstraintFailed(reason),
+        };
+        trace!("Replacement:\n{}", replacement_asm);
+
+        // Assemble at the address of the match so relative branches and RIP-relative operands in
+        // the replacement are encoded correctly
+        let available = pattern_match.end() - pattern_match.start();
+        let mut asm = match nasm_assemble(&replacement_asm, match_start) {
+            Ok(asm) => asm,
+            Err(_) => return ReplacementOutcome::AssemblyFailed,
+        };
+
+        if asm.len() <= available {
+            asm.extend(self.filler_set.pad(available - asm.len()));
+            spans[span_index].code.splice(pattern_match.range(), asm);
+            return ReplacementOutcome::Applied;
+        }
+
+        let too_large = ReplacementOutcome::SkippedTooLarge {
+            replacement: asm.len(),
+            available,
+        };
+        match self.code_caves {
+            Some(ref mut code_caves) => match code_caves.place(
+                spans,
+                span_index,
+                pattern_match.range(),
+                &replacement_asm,
+                &self.filler_set,
+            ) {
+                Ok(trampoline) => ReplacementOutcome::AppliedInCodeCave(trampoline),
+                Err(CodeCaveError::AssemblyFailed) => ReplacementOutcome::AssemblyFailed,
+                Err(error) => {
+                    debug!("Can't use a code cave: {}", error);
+                    too_large
+                }
+            },
+            None => too_large,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unbound_replacement_variable() {
+        let pattern = ObfuscationPattern::new(
+            vec!["push $reg:r1".parse().unwrap()],
+            vec!["mov $reg:r1, $reg:r2".parse().unwrap()],
+        );
+        let variables = vec![InstantiatedVariable::new_register(
+            "r1".to_string(),
+            Register::RBX,
+        )];
+        assert_eq!(
+            instantiate_replacement(&pattern, &variables),
+            Err("unbound variable $reg:r2".to_string())
+        );
+    }
+
+    #[test]
+    fn resolve_clobbers() {
+        let pattern: ObfuscationPattern = serde_json::from_str(
+            r#"{
+                "pattern": ["add $reg:r1, 8"],
+                "replacement": ["lea $reg:r1, [$reg:r1 + 8]"],
+                "clobbers": ["$reg:r2", "flags"]
+            }"#,
+        )
+        .unwrap();
+        let variables = vec![InstantiatedVariable::new_register(
+            "r1".to_string(),
+            Register::RBX,
+        )];
+        assert_eq!(
+            clobbered_locations(&pattern, &variables),
+            Err("unbound variable $reg:r2".to_string())
+        );
+        let variables = vec![InstantiatedVariable::new_register(
+            "r2".to_string(),
+            Register::RCX,
+        )];
+        let clobbered = clobbered_locations(&pattern, &variables).unwrap();
+        assert_eq!(clobbered.len(), 7);
+        assert!(clobbered.contains(&Location::Register(Gpr::Rcx)));
+    }
+}
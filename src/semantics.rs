@@ -0,0 +1,756 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+use failure::Fail;
+use fxhash::FxHashSet;
+
+use crate::disassembly::parse_number;
+use crate::x86::{BranchKind, Flag, Gpr, Location};
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum SemanticsError {
+    #[fail(display = "unsupported instruction: {}", _0)]
+    UnsupportedInstruction(String),
+    #[fail(display = "unsupported operand: {}", _0)]
+    UnsupportedOperand(String),
+    #[fail(
+        display = "memory access at {} may partially overlap an earlier store",
+        _0
+    )]
+    PossibleAliasing(String),
+    #[fail(display = "control transfer before the end of the instruction sequence")]
+    ControlTransferNotLast,
+}
+
+/// Part of a symbolic value which isn't a constant
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Term {
+    /// Unknown value at the start of the execution (e.g. a register or a number variable)
+    Initial(String),
+    /// Result of an operation which can't be expressed as a sum
+    Op(String, Vec<Value>),
+}
+
+/// A symbolic 64-bit value of the form `constant + c1 * t1 + c2 * t2 + ...` using wrapping
+/// arithmetic. Values are kept normalized so equal sums compare equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Value {
+    constant: u64,
+    terms: BTreeMap<Term, u64>,
+}
+
+impl Value {
+    pub fn constant(constant: u64) -> Value {
+        Value {
+            constant,
+            terms: BTreeMap::new(),
+        }
+    }
+
+    pub fn initial(name: &str) -> Value {
+        Value::term(Term::Initial(name.to_string()))
+    }
+
+    /// Result of the operation `name`; folded to a constant if possible
+    pub fn op(name: &str, args: Vec<Value>) -> Value {
+        let constants: Option<Vec<u64>> = args.iter().map(Value::as_constant).collect();
+        if let Some(constant) = constants.and_then(|constants| fold(name, &constants)) {
+            return Value::constant(constant);
+        }
+        Value::term(Term::Op(name.to_string(), args))
+    }
+
+    fn term(term: Term) -> Value {
+        let mut terms = BTreeMap::new();
+        terms.insert(term, 1);
+        Value { constant: 0, terms }
+    }
+
+    pub fn as_constant(&self) -> Option<u64> {
+        if self.terms.is_empty() {
+            Some(self.constant)
+        } else {
+            None
+        }
+    }
+
+    pub fn add(&self, other: &Value) -> Value {
+        let mut sum = self.clone();
+        sum.constant = sum.constant.wrapping_add(other.constant);
+        for (term, coefficient) in &other.terms {
+            let entry = sum.terms.entry(term.clone()).or_insert(0);
+            *entry = entry.wrapping_add(*coefficient);
+            if *entry == 0 {
+                sum.terms.remove(term);
+            }
+        }
+        sum
+    }
+
+    pub fn neg(&self) -> Value {
+        Value {
+            constant: self.constant.wrapping_neg(),
+            terms: self
+                .terms
+                .iter()
+                .map(|(term, coefficient)| (term.clone(), coefficient.wrapping_neg()))
+                .collect(),
+        }
+    }
+
+    pub fn sub(&self, other: &Value) -> Value {
+        self.add(&other.neg())
+    }
+
+    pub fn scale(&self, factor: u64) -> Value {
+        if factor == 0 {
+            return Value::constant(0);
+        }
+        Value {
+            constant: self.constant.wrapping_mul(factor),
+            terms: self
+                .terms
+                .iter()
+                .map(|(term, coefficient)| (term.clone(), coefficient.wrapping_mul(factor)))
+                .collect(),
+        }
+    }
+
+    /// The lower 32 bits zero-extended to 64 bits
+    pub fn trunc32(&self) -> Value {
+        let truncated = match self.terms.iter().next() {
+            Some((Term::Op(name, _), 1)) => name == "trunc32" && self.terms.len() == 1,
+            _ => false,
+        };
+        if truncated && self.constant == 0 {
+            self.clone()
+        } else {
+            Value::op("trunc32", vec![self.clone()])
+        }
+    }
+
+    /// Whether the value is the initial stack pointer plus a constant
+    fn is_on_stack(&self) -> bool {
+        self.terms.len() == 1 && self.terms.get(&Term::Initial("rsp".to_string())) == Some(&1)
+    }
+
+    /// Whether 64-bit accesses at both addresses can't overlap: they differ by a constant of at
+    /// least 8 or only one of them is on the stack (which is assumed not to alias other memory)
+    fn distinct_qwords(&self, other: &Value) -> bool {
+        if self.is_on_stack() != other.is_on_stack() {
+            return true;
+        }
+        self.terms == other.terms && {
+            let difference = self.constant.wrapping_sub(other.constant) as i64;
+            difference >= 8 || difference <= -8
+        }
+    }
+
+    /// Whether the value is below `other` by a constant
+    fn below(&self, other: &Value) -> bool {
+        self.terms == other.terms && (self.constant.wrapping_sub(other.constant) as i64) < 0
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (term, coefficient) in &self.terms {
+            if !first {
+                write!(f, " + ")?;
+            }
+            first = false;
+            if *coefficient != 1 {
+                write!(f, "{}*", *coefficient as i64)?;
+            }
+            match term {
+                Term::Initial(name) => write!(f, "{}", name)?,
+                Term::Op(name, args) => {
+                    write!(f, "{}(", name)?;
+                    for (i, arg) in args.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}", arg)?;
+                    }
+                    write!(f, ")")?;
+                }
+            }
+        }
+        if first {
+            write!(f, "0x{:x}", self.constant)
+        } else if (self.constant as i64) < 0 {
+            write!(f, " - 0x{:x}", self.constant.wrapping_neg())
+        } else if self.constant != 0 {
+            write!(f, " + 0x{:x}", self.constant)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Evaluates the operation `name` on constant arguments
+fn fold(name: &str, args: &[u64]) -> Option<u64> {
+    const LOW32: u64 = 0xFFFF_FFFF;
+    let condition_code = if name.starts_with("jcc") {
+        Some(&name[3..])
+    } else if name.starts_with("cmov") {
+        Some(&name[4..])
+    } else {
+        None
+    };
+    if let Some(condition_code) = condition_code {
+        // Arguments are the flags followed by the value if the condition holds and otherwise
+        let condition_code = u8::from_str_radix(condition_code, 16);
+        return match (condition_code, args) {
+            (Ok(condition_code), [cf, pf, _, zf, sf, of, taken, not_taken]) => {
+                let condition = match condition_code >> 1 {
+                    0 => *of == 1,
+                    1 => *cf == 1,
+                    2 => *zf == 1,
+                    3 => *cf == 1 || *zf == 1,
+                    4 => *sf == 1,
+                    5 => *pf == 1,
+                    6 => sf != of,
+                    _ => *zf == 1 || sf != of,
+                };
+                // Odd condition codes are the negation
+                if condition != (condition_code & 1 == 1) {
+                    Some(*taken)
+                } else {
+                    Some(*not_taken)
+                }
+            }
+            _ => None,
+        };
+    }
+    Some(match (name, args) {
+        ("cf_add", [a, b]) => a.overflowing_add(*b).1 as u64,
+        ("cf_add32", [a, b]) => ((a & LOW32) + (b & LOW32)) >> 32,
+        ("cf_sub", [a, b]) => (a < b) as u64,
+        ("cf_sub32", [a, b]) => ((a & LOW32) < (b & LOW32)) as u64,
+        ("of_add", [a, b]) => (*a as i64).overflowing_add(*b as i64).1 as u64,
+        ("of_add32", [a, b]) => (*a as i32).overflowing_add(*b as i32).1 as u64,
+        ("of_sub", [a, b]) => (*a as i64).overflowing_sub(*b as i64).1 as u64,
+        ("of_sub32", [a, b]) => (*a as i32).overflowing_sub(*b as i32).1 as u64,
+        ("af_add", [a, b]) => ((a ^ b ^ a.wrapping_add(*b)) >> 4) & 1,
+        ("af_sub", [a, b]) => ((a ^ b ^ a.wrapping_sub(*b)) >> 4) & 1,
+        ("trunc32", [a]) => a & LOW32,
+        ("and", [a, b]) => a & b,
+        ("or", [a, b]) => a | b,
+        ("xor", [a, b]) => a ^ b,
+        ("not", [a]) => !a,
+        ("zf", [result]) => (*result == 0) as u64,
+        ("sf", [result]) => result >> 63,
+        ("sf32", [result]) => (result >> 31) & 1,
+        ("pf", [result]) => ((result & 0xFF).count_ones() % 2 == 0) as u64,
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Width {
+    Qword,
+    Dword,
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Register(Gpr, Width),
+    Immediate(Value),
+    Memory(Value, Option<Width>),
+}
+
+/// Symbolic machine state: general-purpose registers, status flags and the memory written so far
+#[derive(Debug, Clone)]
+pub struct State {
+    registers: BTreeMap<Gpr, Value>,
+    flags: BTreeMap<Flag, Value>,
+    /// Stores in the order in which they were executed
+    stores: Vec<(Value, Value)>,
+    /// Target of the control transfer which ended the execution
+    next: Option<Value>,
+}
+
+impl State {
+    /// State in which every register and flag has its (symbolic) initial value
+    pub fn new() -> State {
+        State {
+            registers: Gpr::all()
+                .iter()
+                .map(|&register| (register, Value::initial(register.name())))
+                .collect(),
+            flags: Flag::all()
+                .iter()
+                .map(|&flag| (flag, Value::initial(flag.name())))
+                .collect(),
+            stores: Vec::new(),
+            next: None,
+        }
+    }
+
+    /// State with the given initial values of all registers and flags (in the order of
+    /// `Gpr::all()` and `Flag::all()`). As all operations on constants are folded this executes
+    /// instructions concretely.
+    pub fn concrete(registers: &[u64], flags: &[bool]) -> State {
+        State {
+            registers: Gpr::all()
+                .iter()
+                .zip(registers)
+                .map(|(&register, &value)| (register, Value::constant(value)))
+                .collect(),
+            flags: Flag::all()
+                .iter()
+                .zip(flags)
+                .map(|(&flag, &value)| (flag, Value::constant(value as u64)))
+                .collect(),
+            stores: Vec::new(),
+            next: None,
+        }
+    }
+
+    pub fn register(&self, register: Gpr) -> &Value {
+        &self.registers[&register]
+    }
+
+    pub fn flag(&self, flag: Flag) -> &Value {
+        &self.flags[&flag]
+    }
+
+    /// Executes the instruction `text` (Intel syntax) whose end is located at `end`. Operands may
+    /// contain `$num:name` and `$label:name` variables which are treated as unknown constants.
+    pub fn execute(&mut self, text: &str, end: &Value) -> Result<(), SemanticsError> {
+        if self.next.is_some() {
+            return Err(SemanticsError::ControlTransferNotLast);
+        }
+        let text = text.trim().to_ascii_lowercase();
+        let (mnemonic, operands) = match text.find(' ') {
+            Some(index) => (&text[..index], text[index..].trim()),
+            None => (text.as_str(), ""),
+        };
+        // Multi-byte NOPs may have operands which aren't supported otherwise
+        if mnemonic == "nop" {
+            return Ok(());
+        }
+        let operands = if operands.is_empty() {
+            Vec::new()
+        } else {
+            operands
+                .split(',')
+                .map(|operand| self.parse_operand(operand.trim(), end))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let unsupported = || SemanticsError::UnsupportedInstruction(text.clone());
+
+        match (mnemonic, &operands[..]) {
+            ("mov", [destination, source]) | ("movabs", [destination, source]) => {
+                let width = operand_width(destination, source);
+                let value = self.read(source, width)?;
+                self.write(destination, value, width)?;
+            }
+            ("lea", [destination, Operand::Memory(address, _)]) => {
+                let width = operand_width(destination, destination);
+                self.write(destination, address.clone(), width)?;
+            }
+            ("push", [source]) => {
+                let value = self.read(source, Width::Qword)?;
+                self.push(value);
+            }
+            ("pop", [destination]) => {
+                let value = self.pop()?;
+                self.write(destination, value, Width::Qword)?;
+            }
+            ("xchg", [a, b]) => {
+                let width = operand_width(a, b);
+                let (value_a, value_b) = (self.read(a, width)?, self.read(b, width)?);
+                self.write(a, value_b, width)?;
+                self.write(b, value_a, width)?;
+            }
+            ("add", [a, b]) | ("sub", [a, b]) | ("cmp", [a, b]) => {
+                let width = operand_width(a, b);
+                let (value_a, value_b) = (self.read(a, width)?, self.read(b, width)?);
+                let kind = if mnemonic == "add" { "add" } else { "sub" };
+                let result = if kind == "add" {
+                    value_a.add(&value_b)
+                } else {
+                    value_a.sub(&value_b)
+                };
+                let result = truncate(result, width);
+                self.set_arithmetic_flags(kind, &value_a, &value_b, &result, width, true);
+                if mnemonic != "cmp" {
+                    self.write(a, result, width)?;
+                }
+            }
+            ("inc", [a]) | ("dec", [a]) => {
+                let width = operand_width(a, a);
+                let value = self.read(a, width)?;
+                let (kind, result) = if mnemonic == "inc" {
+                    ("add", value.add(&Value::constant(1)))
+                } else {
+                    ("sub", value.sub(&Value::constant(1)))
+                };
+                let result = truncate(result, width);
+                self.set_arithmetic_flags(kind, &value, &Value::constant(1), &result, width, false);
+                self.write(a, result, width)?;
+            }
+            ("neg", [a]) => {
+                let width = operand_width(a, a);
+                let value = self.read(a, width)?;
+                let result = truncate(value.neg(), width);
+                self.set_arithmetic_flags("sub", &Value::constant(0), &value, &result, width, true);
+                self.write(a, result, width)?;
+            }
+            ("and", [a, b]) | ("or", [a, b]) | ("xor", [a, b]) | ("test", [a, b]) => {
+                let width = operand_width(a, b);
+                let (value_a, value_b) = (self.read(a, width)?, self.read(b, width)?);
+                let result = if mnemonic == "xor" && value_a == value_b {
+                    Value::constant(0)
+                } else {
+                    let name = if mnemonic == "test" { "and" } else { mnemonic };
+                    Value::op(name, vec![value_a, value_b])
+                };
+                let result = truncate(result, width);
+                self.set_result_flags(&result, width);
+                self.flags.insert(Flag::Cf, Value::constant(0));
+                self.flags.insert(Flag::Of, Value::constant(0));
+                self.flags
+                    .insert(Flag::Af, Value::op("undefined_af", vec![result.clone()]));
+                if mnemonic != "test" {
+                    self.write(a, result, width)?;
+                }
+            }
+            ("not", [a]) => {
+                let width = operand_width(a, a);
+                let result = truncate(Value::op("not", vec![self.read(a, width)?]), width);
+                self.write(a, result, width)?;
+            }
+            ("ret", []) => self.next = Some(self.pop()?),
+            ("jmp", [target]) => self.next = Some(self.read(target, Width::Qword)?),
+            ("call", [target]) => {
+                let target = self.read(target, Width::Qword)?;
+                self.push(end.clone());
+                self.next = Some(target);
+            }
+            (_, [destination, source]) if mnemonic.starts_with("cmov") => {
+                let condition_code =
+                    match BranchKind::from_mnemonic(&format!("j{}", &mnemonic[4..])) {
+                        Some(BranchKind::Jcc(condition_code)) => condition_code,
+                        _ => return Err(unsupported()),
+                    };
+                let width = operand_width(destination, source);
+                let mut args = self.flag_values();
+                args.push(self.read(source, width)?);
+                args.push(self.read(destination, width)?);
+                let value = Value::op(&format!("cmov{:x}", condition_code), args);
+                self.write(destination, value, width)?;
+            }
+            (_, [target]) => match BranchKind::from_mnemonic(mnemonic) {
+                Some(BranchKind::Jcc(condition_code)) => {
+                    let mut args = self.flag_values();
+                    args.push(self.read(target, Width::Qword)?);
+                    args.push(end.clone());
+                    self.next = Some(Value::op(&format!("jcc{:x}", condition_code), args));
+                }
+                _ => return Err(unsupported()),
+            },
+            _ => return Err(unsupported()),
+        }
+        Ok(())
+    }
+
+    /// Target of the control transfer which ended the execution so far; execution can be continued
+    /// there after taking it
+    pub fn take_next(&mut self) -> Option<Value> {
+        self.next.take()
+    }
+
+    /// Finishes the execution of a sequence whose end is located at `end`
+    pub fn finish(&mut self, end: &Value) {
+        if self.next.is_none() {
+            self.next = Some(end.clone());
+        }
+    }
+
+    /// Returns the locations (registers, flags, memory and the next instruction) whose values
+    /// differ between the two states, except for `ignored`. Stores below the final stack pointer
+    /// aren't compared as that memory is free.
+    pub fn differences(&self, other: &State, ignored: &FxHashSet<Location>) -> Vec<String> {
+        let mut differences = Vec::new();
+        for register in Gpr::all() {
+            if self.registers[register] != other.registers[register]
+                && !ignored.contains(&Location::Register(*register))
+            {
+                differences.push(register.name().to_string());
+            }
+        }
+        for flag in Flag::all() {
+            if self.flags[flag] != other.flags[flag] && !ignored.contains(&Location::Flag(*flag)) {
+                differences.push(flag.name().to_string());
+            }
+        }
+        let (memory, other_memory) = (self.memory(), other.memory());
+        for address in memory.keys().chain(other_memory.keys()) {
+            let difference = format!("[{}]", address);
+            if memory.get(address) != other_memory.get(address)
+                && !differences.contains(&difference)
+            {
+                differences.push(difference);
+            }
+        }
+        if self.next != other.next {
+            differences.push("next instruction".to_string());
+        }
+        differences
+    }
+
+    /// Last value stored to each address which is still part of the stack (or not on it at all)
+    fn memory(&self) -> BTreeMap<Value, Value> {
+        let stack_pointer = &self.registers[&Gpr::Rsp];
+        let mut memory = BTreeMap::new();
+        for (address, value) in &self.stores {
+            memory.insert(address.clone(), value.clone());
+        }
+        memory
+            .into_iter()
+            .filter(|(address, _)| !address.below(stack_pointer))
+            .collect()
+    }
+
+    /// Values of all flags in the order of `Flag::all()`; used as arguments of conditional
+    /// operations
+    fn flag_values(&self) -> Vec<Value> {
+        Flag::all()
+            .iter()
+            .map(|flag| self.flags[flag].clone())
+            .collect()
+    }
+
+    fn push(&mut self, value: Value) {
+        let stack_pointer = self.registers[&Gpr::Rsp].sub(&Value::constant(8));
+        self.registers.insert(Gpr::Rsp, stack_pointer.clone());
+        self.stores.push((stack_pointer, value));
+    }
+
+    fn pop(&mut self) -> Result<Value, SemanticsError> {
+        let stack_pointer = self.registers[&Gpr::Rsp].clone();
+        let value = self.load(&stack_pointer)?;
+        self.registers
+            .insert(Gpr::Rsp, stack_pointer.add(&Value::constant(8)));
+        Ok(value)
+    }
+
+    fn load(&self, address: &Value) -> Result<Value, SemanticsError> {
+        for (store_address, value) in self.stores.iter().rev() {
+            if store_address == address {
+                return Ok(value.clone());
+            }
+            if !store_address.distinct_qwords(address) {
+                return Err(SemanticsError::PossibleAliasing(address.to_string()));
+            }
+        }
+        Ok(Value::op("memory", vec![address.clone()]))
+    }
+
+    fn read(&self, operand: &Operand, width: Width) -> Result<Value, SemanticsError> {
+        let value = match operand {
+            Operand::Register(register, _) => self.registers[register].clone(),
+            Operand::Immediate(value) => return Ok(value.clone()),
+            Operand::Memory(address, _) => self.load(address)?,
+        };
+        Ok(truncate(value, width))
+    }
+
+    fn write(
+        &mut self,
+        operand: &Operand,
+        value: Value,
+        width: Width,
+    ) -> Result<(), SemanticsError> {
+        match operand {
+            // Writes to 32-bit registers zero-extend
+            Operand::Register(register, _) => {
+                self.registers.insert(*register, truncate(value, width));
+            }
+            Operand::Memory(address, _) if width == Width::Qword => {
+                self.stores.push((address.clone(), value));
+            }
+            _ => {
+                return Err(SemanticsError::UnsupportedOperand(format!("{:?}", operand)));
+            }
+        }
+        Ok(())
+    }
+
+    fn set_result_flags(&mut self, result: &Value, width: Width) {
+        let sign = if width == Width::Dword { "sf32" } else { "sf" };
+        self.flags
+            .insert(Flag::Zf, Value::op("zf", vec![result.clone()]));
+        self.flags
+            .insert(Flag::Sf, Value::op(sign, vec![result.clone()]));
+        self.flags
+            .insert(Flag::Pf, Value::op("pf", vec![result.clone()]));
+    }
+
+    fn set_arithmetic_flags(
+        &mut self,
+        kind: &str,
+        a: &Value,
+        b: &Value,
+        result: &Value,
+        width: Width,
+        carry: bool,
+    ) {
+        let suffix = if width == Width::Dword { "32" } else { "" };
+        self.set_result_flags(result, width);
+        let args = vec![a.clone(), b.clone()];
+        self.flags.insert(
+            Flag::Of,
+            Value::op(&format!("of_{}{}", kind, suffix), args.clone()),
+        );
+        self.flags
+            .insert(Flag::Af, Value::op(&format!("af_{}", kind), args.clone()));
+        if carry {
+            self.flags
+                .insert(Flag::Cf, Value::op(&format!("cf_{}{}", kind, suffix), args));
+        }
+    }
+
+    fn parse_operand(&self, operand: &str, end: &Value) -> Result<Operand, SemanticsError> {
+        let unsupported = || SemanticsError::UnsupportedOperand(operand.to_string());
+        let (width, rest) = if operand.starts_with("qword ptr") {
+            (Some(Width::Qword), operand["qword ptr".len()..].trim())
+        } else if operand.starts_with("dword ptr") {
+            (Some(Width::Dword), operand["dword ptr".len()..].trim())
+        } else {
+            (None, operand)
+        };
+
+        if rest.starts_with('[') && rest.ends_with(']') {
+            let address = self
+                .parse_expression(&rest[1..rest.len() - 1], end)
+                .ok_or_else(unsupported)?;
+            return Ok(Operand::Memory(address, width));
+        }
+        if let Some(register) = Gpr::from_register_name(rest) {
+            // 8- and 16-bit registers aren't supported
+            let width = if rest.starts_with('e') || rest.starts_with('r') && rest.ends_with('d') {
+                Width::Dword
+            } else if rest.starts_with('r') && !rest.ends_with(&['w', 'b'][..]) {
+                Width::Qword
+            } else {
+                return Err(unsupported());
+            };
+            return Ok(Operand::Register(register, width));
+        }
+        self.parse_expression(rest, end)
+            .map(Operand::Immediate)
+            .ok_or_else(unsupported)
+    }
+
+    /// Parses a sum of registers (optionally scaled), numbers and variables. Registers have their
+    /// current value; `rip` is `end`.
+    fn parse_expression(&self, expression: &str, end: &Value) -> Option<Value> {
+        let mut sum = Value::constant(0);
+        for term in expression.replace('-', "+-").split('+') {
+            let term = term.trim();
+            if term.is_empty() {
+                continue;
+            }
+            let (negative, term) = if term.starts_with('-') {
+                (true, term[1..].trim())
+            } else {
+                (false, term)
+            };
+            let mut product = Value::constant(1);
+            let mut scale = 1u64;
+            for factor in term.split('*').map(str::trim) {
+                if let Some(number) = parse_number(factor) {
+                    scale = scale.wrapping_mul(number);
+                } else if product.as_constant() == Some(1) {
+                    product = if factor == "rip" {
+                        end.clone()
+                    } else if factor.starts_with('$') {
+                        Value::initial(factor)
+                    } else {
+                        self.registers[&Gpr::from_register_name(factor)?].clone()
+                    };
+                } else {
+                    return None;
+                }
+            }
+            let value = product.scale(scale);
+            sum = if negative {
+                sum.sub(&value)
+            } else {
+                sum.add(&value)
+            };
+        }
+        Some(sum)
+    }
+}
+
+impl Default for State {
+    fn default() -> State {
+        State::new()
+    }
+}
+
+fn truncate(value: Value, width: Width) -> Value {
+    match width {
+        Width::Qword => value,
+        Width::Dword => value.trunc32(),
+    }
+}
+
+/// Width of an operation on `a` and `b`; registers determine it before memory operand sizes
+fn operand_width(a: &Operand, b: &Operand) -> Width {
+    match (a, b) {
+        (Operand::Register(_, width), _) | (_, Operand::Register(_, width)) => *width,
+        (Operand::Memory(_, Some(width)), _) | (_, Operand::Memory(_, Some(width))) => *width,
+        _ => Width::Qword,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn execute(instructions: &[(&str, u64)]) -> State {
+        let mut state = State::new();
+        let mut end = Value::initial("start");
+        for (text, length) in instructions {
+            end = end.add(&Value::constant(*length));
+            state.execute(text, &end).unwrap();
+        }
+        state.finish(&end);
+        state
+    }
+
+    #[test]
+    fn stack_operations() {
+        // The replacement is padded to the length of the pattern
+        let push = execute(&[("push rbx", 1), ("nop", 8)]);
+        let lea_mov = execute(&[("lea rsp, [rsp - 8]", 5), ("mov [rsp], rbx", 4)]);
+        assert!(push.differences(&lea_mov, &FxHashSet::default()).is_empty());
+
+        let lea_mov_rsp = execute(&[("lea rsp, [rsp - 8]", 5), ("mov [rsp], rsp", 4)]);
+        let push_rsp = execute(&[("push rsp", 1), ("nop", 8)]);
+        assert_eq!(
+            push_rsp.differences(&lea_mov_rsp, &FxHashSet::default()),
+            vec!["[rsp - 0x8]".to_string()]
+        );
+    }
+
+    #[test]
+    fn flags_and_variables() {
+        let add_sub = execute(&[("add rcx, $num:n", 7), ("sub rcx, $num:n", 7)]);
+        let empty = execute(&[("nop", 14)]);
+        let flags: FxHashSet<_> = Location::flags().collect();
+        assert_eq!(add_sub.differences(&empty, &FxHashSet::default()).len(), 6);
+        assert!(add_sub.differences(&empty, &flags).is_empty());
+
+        let xor = execute(&[("xor eax, eax", 2)]);
+        assert_eq!(xor.register(Gpr::Rax), &Value::constant(0));
+        assert_eq!(xor.flag(Flag::Zf), &Value::constant(1));
+    }
+}
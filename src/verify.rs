@@ -0,0 +1,407 @@
+use std::fmt::{self, Display};
+
+use fxhash::FxHashMap;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+use crate::keystone_assemble;
+use crate::pattern::*;
+use crate::rewrite::clobbered_locations;
+use crate::semantics::{State, Value};
+
+/// Maximum number of instruction sequences a pattern's alternatives and repetitions expand to
+const MAX_EXPANSIONS: usize = 256;
+/// Values number and label variables are replaced with to determine the instruction lengths; one
+/// for each encoding size of immediates and displacements (8, 32 and 64 bits)
+const LENGTH_PLACEHOLDERS: [&str; 3] = ["0x10", "0x1000", "0x123456789"];
+/// Maximum number of number and label variables; each one multiplies the checked instantiations
+const MAX_SYMBOLIC_VARIABLES: usize = 4;
+
+/// Result of comparing the effects of a pattern with those of its replacement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Both have the same effects for all checked instantiations
+    Equivalent {
+        instantiations: usize,
+    },
+    Differs {
+        instantiation: String,
+        differences: Vec<String>,
+    },
+    Unsupported(String),
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Equivalent { instantiations } => {
+                write!(f, "equivalent (checked {} instantiations)", instantiations)
+            }
+            Verdict::Differs {
+                instantiation,
+                differences,
+            } => write!(
+                f,
+                "differs in {} for {}",
+                differences.join(", "),
+                instantiation
+            ),
+            Verdict::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+        }
+    }
+}
+
+/// Symbolically executes the pattern and its replacement for every instantiation of the register
+/// variables and compares their effects on registers, flags, (non-free) stack memory and the
+/// next instruction. Number and label variables stay symbolic but the instruction lengths are
+/// determined for each of their encoding sizes. Instantiations which can't be assembled or are
+/// excluded are skipped as the pattern can't match them; clobbered locations aren't compared.
+pub fn verify(pattern: &ObfuscationPattern) -> Verdict {
+    if !pattern.blocks().is_empty() {
+        return Verdict::Unsupported("patterns with blocks".to_string());
+    }
+    let expansions = match expand(pattern.pattern()) {
+        Ok(expansions) => expansions,
+        Err(reason) => return Verdict::Unsupported(reason),
+    };
+    let exclusions = match pattern.exclusions() {
+        Ok(exclusions) => exclusions,
+        Err(error) => return Verdict::Unsupported(error.to_string()),
+    };
+
+    let mut register_variables: Vec<String> = Vec::new();
+    let mut symbolic_variables: Vec<String> = Vec::new();
+    let all_instructions = expansions
+        .iter()
+        .flatten()
+        .cloned()
+        .chain(pattern.replacement());
+    for variable in all_instructions.flat_map(InstructionPattern::variables) {
+        match variable.typee() {
+            VariableType::Register => {
+                if !register_variables
+                    .iter()
+                    .any(|name| name == variable.name())
+                {
+                    register_variables.push(variable.name().to_string());
+                }
+            }
+            VariableType::Number | VariableType::Label => {
+                let variable = variable.to_string();
+                if !symbolic_variables.contains(&variable) {
+                    symbolic_variables.push(variable);
+                }
+            }
+            typee => {
+                return Verdict::Unsupported(format!("{:?} variables", typee));
+            }
+        }
+    }
+
+    if symbolic_variables.len() > MAX_SYMBOLIC_VARIABLES {
+        return Verdict::Unsupported(format!(
+            "more than {} number and label variables",
+            MAX_SYMBOLIC_VARIABLES
+        ));
+    }
+
+    let mut lengths = FxHashMap::default();
+    let mut instantiations = 0;
+    for expansion in &expansions {
+        for assignment in assignments(register_variables.len()) {
+            // The pattern doesn't match excluded instantiations
+            let excluded = register_variables
+                .iter()
+                .zip(&assignment)
+                .any(|(name, register)| exclusions.contains(&(name.clone(), *register)));
+            if excluded {
+                continue;
+            }
+            let variables: Vec<_> = register_variables
+                .iter()
+                .zip(&assignment)
+                .map(|(name, register)| InstantiatedVariable::new_register(name.clone(), *register))
+                .collect();
+            let instantiate = |instructions: &[&InstructionPattern]| -> Vec<String> {
+                instructions
+                    .iter()
+                    .map(|instruction| {
+                        let mut text = instruction.pattern().to_string();
+                        for variable in &variables {
+                            text = text.replace(
+                                &variable.as_variable().to_string(),
+                                &variable.value().to_ascii_lowercase(),
+                            );
+                        }
+                        text
+                    })
+                    .collect()
+            };
+            let pattern_text = instantiate(expansion);
+            let replacement_text = instantiate(&pattern.replacement().iter().collect::<Vec<_>>());
+
+            // The length of an instruction may depend on the value of its number and label
+            // variables so each combination of encoding sizes is checked separately
+            let mut checked_lengths = Vec::new();
+            for placeholders in placeholder_assignments(&symbolic_variables) {
+                let pattern_lengths =
+                    match instruction_lengths(&pattern_text, &placeholders, &mut lengths) {
+                        Some(pattern_lengths) => pattern_lengths,
+                        None => continue,
+                    };
+                let instantiation = describe(&variables, &placeholders);
+                let replacement_lengths =
+                    match instruction_lengths(&replacement_text, &placeholders, &mut lengths) {
+                        Some(replacement_lengths) => replacement_lengths,
+                        None => {
+                            return Verdict::Differs {
+                                instantiation,
+                                differences: vec!["assembly of the replacement".to_string()],
+                            }
+                        }
+                    };
+                if checked_lengths.contains(&(pattern_lengths.clone(), replacement_lengths.clone()))
+                {
+                    continue;
+                }
+                // The replacement is padded with filler to the length of the matched code
+                let end = Value::initial("start").add(&Value::constant(
+                    pattern_lengths.iter().sum::<usize>() as u64,
+                ));
+                let pattern_state = match execute(&pattern_text, &pattern_lengths, &end) {
+                    Ok(state) => state,
+                    Err(reason) => return Verdict::Unsupported(reason),
+                };
+                let replacement_state = match execute(&replacement_text, &replacement_lengths, &end)
+                {
+                    Ok(state) => state,
+                    Err(reason) => return Verdict::Unsupported(reason),
+                };
+
+                let clobbered = match clobbered_locations(pattern, &variables) {
+                    Ok(clobbered) => clobbered,
+                    Err(reason) => return Verdict::Unsupported(reason),
+                };
+                let differences = pattern_state.differences(&replacement_state, &clobbered);
+                if !differences.is_empty() {
+                    return Verdict::Differs {
+                        instantiation,
+                        differences,
+                    };
+                }
+                checked_lengths.push((pattern_lengths, replacement_lengths));
+                instantiations += 1;
+            }
+        }
+    }
+    if instantiations == 0 {
+        return Verdict::Unsupported("no instantiation can be assembled".to_string());
+    }
+    Verdict::Equivalent { instantiations }
+}
+
+/// Expands alternatives and repetitions into plain instruction sequences
+pub(crate) fn expand(elements: &[PatternElement]) -> Result<Vec<Vec<&InstructionPattern>>, String> {
+    let mut expansions = vec![Vec::new()];
+    for element in elements {
+        let element_expansions: Vec<Vec<&InstructionPattern>> = match element {
+            PatternElement::Instruction(instruction) => vec![vec![instruction]],
+            PatternElement::Sequence(elements) => expand(elements)?,
+            PatternElement::AnyOf { any_of } => {
+                let mut alternatives = Vec::new();
+                for alternative in any_of {
+                    alternatives.extend(expand(std::slice::from_ref(alternative))?);
+                }
+                alternatives
+            }
+            PatternElement::Repeat { repeat, min, max } => {
+                let body = expand(repeat)?;
+                let mut repetitions = Vec::new();
+                let mut current = vec![Vec::new()];
+                for count in 0..=*max {
+                    if count >= *min {
+                        repetitions.extend(current.iter().cloned());
+                    }
+                    if count < *max {
+                        current = concatenate(&current, &body)?;
+                    }
+                }
+                repetitions
+            }
+            PatternElement::Wildcard { .. } => return Err("wildcards".to_string()),
+        };
+        expansions = concatenate(&expansions, &element_expansions)?;
+    }
+    Ok(expansions)
+}
+
+fn concatenate<'a>(
+    prefixes: &[Vec<&'a InstructionPattern>],
+    suffixes: &[Vec<&'a InstructionPattern>],
+) -> Result<Vec<Vec<&'a InstructionPattern>>, String> {
+    if prefixes.len() * suffixes.len() > MAX_EXPANSIONS {
+        return Err(format!("more than {} expansions", MAX_EXPANSIONS));
+    }
+    Ok(prefixes
+        .iter()
+        .flat_map(|prefix| {
+            suffixes.iter().map(move |suffix| {
+                let mut sequence = prefix.clone();
+                sequence.extend(suffix.iter().cloned());
+                sequence
+            })
+        })
+        .collect())
+}
+
+/// All assignments of registers to `count` variables
+fn assignments(count: usize) -> Vec<Vec<Register>> {
+    let mut assignments = vec![Vec::new()];
+    for _ in 0..count {
+        assignments = assignments
+            .iter()
+            .flat_map(|assignment| {
+                Register::all().iter().map(move |register| {
+                    let mut assignment = assignment.clone();
+                    assignment.push(*register);
+                    assignment
+                })
+            })
+            .collect();
+    }
+    assignments
+}
+
+/// All assignments of length placeholders to the number and label `variables`
+fn placeholder_assignments(variables: &[String]) -> Vec<Vec<(&str, &'static str)>> {
+    let mut assignments = vec![Vec::new()];
+    for variable in variables {
+        assignments = assignments
+            .iter()
+            .flat_map(|assignment| {
+                LENGTH_PLACEHOLDERS.iter().map(move |placeholder| {
+                    let mut assignment = assignment.clone();
+                    assignment.push((variable.as_str(), *placeholder));
+                    assignment
+                })
+            })
+            .collect();
+    }
+    assignments
+}
+
+/// Lengths of the instructions when the number and label variables are replaced with the given
+/// placeholders. Returns `None` if an instruction can't be assembled.
+fn instruction_lengths(
+    instructions: &[String],
+    placeholders: &[(&str, &str)],
+    cache: &mut FxHashMap<String, Option<usize>>,
+) -> Option<Vec<usize>> {
+    lazy_static! {
+        static ref SYMBOLIC: Regex = Regex::new(r"\$(num|label):\w+").unwrap();
+    }
+    instructions
+        .iter()
+        .map(|instruction| {
+            let concrete = SYMBOLIC
+                .replace_all(instruction, |captures: &Captures<'_>| {
+                    placeholders
+                        .iter()
+                        .find(|(variable, _)| *variable == &captures[0])
+                        .map_or("0", |(_, placeholder)| placeholder)
+                        .to_string()
+                })
+                .into_owned();
+            *cache.entry(concrete.clone()).or_insert_with(|| {
+                keystone_assemble(concrete, 0)
+                    .ok()
+                    .map(|assembled| assembled.bytes.len())
+            })
+        })
+        .collect()
+}
+
+fn execute(instructions: &[String], lengths: &[usize], end: &Value) -> Result<State, String> {
+    let mut state = State::new();
+    let mut instruction_end = Value::initial("start");
+    for (instruction, length) in instructions.iter().zip(lengths) {
+        instruction_end = instruction_end.add(&Value::constant(*length as u64));
+        state
+            .execute(instruction, &instruction_end)
+            .map_err(|error| error.to_string())?;
+    }
+    state.finish(end);
+    Ok(state)
+}
+
+fn describe(variables: &[InstantiatedVariable], placeholders: &[(&str, &str)]) -> String {
+    let mut description = if variables.is_empty() {
+        "all values".to_string()
+    } else {
+        variables
+            .iter()
+            .map(|variable| format!("{} = {}", variable.as_variable(), variable.value()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if !placeholders.is_empty() {
+        description += &format!(
+            " (with the lengths for {})",
+            placeholders
+                .iter()
+                .map(|(variable, placeholder)| format!("{} = {}", variable, placeholder))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_push_pop() {
+        let pattern = ObfuscationPattern::new(
+            vec![
+                "push $reg:r1".parse().unwrap(),
+                "pop $reg:r1".parse().unwrap(),
+            ],
+            vec![],
+        );
+        // Only the 64-bit registers can be pushed
+        assert_eq!(verify(&pattern), Verdict::Equivalent { instantiations: 8 });
+        let mut excluding = pattern.clone();
+        excluding.set_excluded(vec!["$reg:r1 != rsp".to_string()]);
+        assert_eq!(
+            verify(&excluding),
+            Verdict::Equivalent { instantiations: 7 }
+        );
+
+        let pattern = ObfuscationPattern::new(vec!["push $reg:r1".parse().unwrap()], vec![]);
+        match verify(&pattern) {
+            Verdict::Differs { differences, .. } => {
+                assert_eq!(
+                    differences,
+                    vec!["rsp".to_string(), "[rsp - 0x8]".to_string()]
+                )
+            }
+            verdict => panic!("unexpected verdict: {}", verdict),
+        }
+    }
+
+    #[test]
+    fn verify_each_encoding_size() {
+        let mut pattern = ObfuscationPattern::new(
+            vec![
+                "push $num:n".parse().unwrap(),
+                "add rsp, 8".parse().unwrap(),
+            ],
+            vec![],
+        );
+        pattern.set_clobbers(vec!["flags".to_string()]);
+        // `push imm8` and `push imm32`; there's no `push imm64`
+        assert_eq!(verify(&pattern), Verdict::Equivalent { instantiations: 2 });
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt::{self, Display};
+
+/// A general-purpose register including all of its sub-registers (e.g. `Gpr::Rax` covers `rax`,
+/// `eax`, `ax`, `ah` and `al`). The variants are ordered by their encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Gpr {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Gpr {
+    pub fn all() -> &'static [Gpr] {
+        &[
+            Gpr::Rax,
+            Gpr::Rcx,
+            Gpr::Rdx,
+            Gpr::Rbx,
+            Gpr::Rsp,
+            Gpr::Rbp,
+            Gpr::Rsi,
+            Gpr::Rdi,
+            Gpr::R8,
+            Gpr::R9,
+            Gpr::R10,
+            Gpr::R11,
+            Gpr::R12,
+            Gpr::R13,
+            Gpr::R14,
+            Gpr::R15,
+        ]
+    }
+
+    /// Name of the full 64-bit register
+    pub fn name(self) -> &'static str {
+        match self {
+            Gpr::Rax => "rax",
+            Gpr::Rcx => "rcx",
+            Gpr::Rdx => "rdx",
+            Gpr::Rbx => "rbx",
+            Gpr::Rsp => "rsp",
+            Gpr::Rbp => "rbp",
+            Gpr::Rsi => "rsi",
+            Gpr::Rdi => "rdi",
+            Gpr::R8 => "r8",
+            Gpr::R9 => "r9",
+            Gpr::R10 => "r10",
+            Gpr::R11 => "r11",
+            Gpr::R12 => "r12",
+            Gpr::R13 => "r13",
+            Gpr::R14 => "r14",
+            Gpr::R15 => "r15",
+        }
+    }
+
+    /// Returns the register which `name` refers to. `name` may be any sub-register name (`ebx`,
+    /// `r9w`, `sil`, ...) and is matched case-insensitively.
+    pub fn from_register_name(name: &str) -> Option<Gpr> {
+        let name = name.to_ascii_lowercase();
+
+        if name.starts_with('r') && name[1..].starts_with(|c: char| c.is_ascii_digit()) {
+            // r8 - r15 with an optional d/w/b suffix
+            let digits = name[1..].trim_end_matches(&['d', 'w', 'b'][..]);
+            if name.len() - 1 - digits.len() > 1 {
+                return None;
+            }
+            return match digits.parse::<usize>() {
+                Ok(n @ 8..=15) => Some(Gpr::all()[n]),
+                _ => None,
+            };
+        }
+
+        match name.as_str() {
+            "rax" | "eax" | "ax" | "ah" | "al" => Some(Gpr::Rax),
+            "rcx" | "ecx" | "cx" | "ch" | "cl" => Some(Gpr::Rcx),
+            "rdx" | "edx" | "dx" | "dh" | "dl" => Some(Gpr::Rdx),
+            "rbx" | "ebx" | "bx" | "bh" | "bl" => Some(Gpr::Rbx),
+            "rsp" | "esp" | "sp" | "spl" => Some(Gpr::Rsp),
+            "rbp" | "ebp" | "bp" | "bpl" => Some(Gpr::Rbp),
+            "rsi" | "esi" | "si" | "sil" => Some(Gpr::Rsi),
+            "rdi" | "edi" | "di" | "dil" => Some(Gpr::Rdi),
+            _ => None,
+        }
+    }
+}
+
+/// A status flag in RFLAGS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Flag {
+    Cf,
+    Pf,
+    Af,
+    Zf,
+    Sf,
+    Of,
+}
+
+impl Flag {
+    pub fn all() -> &'static [Flag] {
+        &[Flag::Cf, Flag::Pf, Flag::Af, Flag::Zf, Flag::Sf, Flag::Of]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Flag::Cf => "cf",
+            Flag::Pf => "pf",
+            Flag::Af => "af",
+            Flag::Zf => "zf",
+            Flag::Sf => "sf",
+            Flag::Of => "of",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Flag> {
+        let name = name.to_ascii_lowercase();
+        Flag::all().iter().cloned().find(|flag| flag.name() == name)
+    }
+}
+
+/// A register or flag whose value may be read or written by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Location {
+    Register(Gpr),
+    Flag(Flag),
+}
+
+impl Location {
+    /// All status flags
+    pub fn flags() -> impl Iterator<Item = Location> {
+        Flag::all().iter().cloned().map(Location::Flag)
+    }
+
+    /// Returns the locations which `name` refers to: a (sub-)register name, a flag name or `flags`
+    /// for all status flags
+    pub fn from_name(name: &str) -> Option<Vec<Location>> {
+        if name.eq_ignore_ascii_case("flags") {
+            return Some(Location::flags().collect());
+        }
+        Gpr::from_register_name(name)
+            .map(Location::Register)
+            .or_else(|| Flag::from_name(name).map(Location::Flag))
+            .map(|location| vec![location])
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Register(register) => write!(f, "{}", register.name()),
+            Location::Flag(flag) => write!(f, "{}", flag.name()),
+        }
+    }
+}
+
+/// A direct branch instruction with a relative target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BranchKind {
+    Jmp,
+    Call,
+    /// Conditional jump with the given condition code (e.g. 0x4 for `je`/`jz`)
+    Jcc(u8),
+}
+
+impl BranchKind {
+    /// Returns the branch kind of `mnemonic`. All aliases of a conditional jump (e.g. `je` and `jz`)
+    /// result in the same kind.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<BranchKind> {
+        let condition_code = match mnemonic.to_ascii_lowercase().as_str() {
+            "jmp" => return Some(BranchKind::Jmp),
+            "call" => return Some(BranchKind::Call),
+            "jo" => 0x0,
+            "jno" => 0x1,
+            "jb" | "jc" | "jnae" => 0x2,
+            "jae" | "jnb" | "jnc" => 0x3,
+            "je" | "jz" => 0x4,
+            "jne" | "jnz" => 0x5,
+            "jbe" | "jna" => 0x6,
+            "ja" | "jnbe" => 0x7,
+            "js" => 0x8,
+            "jns" => 0x9,
+            "jp" | "jpe" => 0xA,
+            "jnp" | "jpo" => 0xB,
+            "jl" | "jnge" => 0xC,
+            "jge" | "jnl" => 0xD,
+            "jle" | "jng" => 0xE,
+            "jg" | "jnle" => 0xF,
+            _ => return None,
+        };
+        Some(BranchKind::Jcc(condition_code))
+    }
+
+    /// Opcodes of the rel8 (if there is one) and rel32 encodings
+    pub fn opcodes(self) -> (Option<u8>, &'static [u8]) {
+        const JCC_REL32: [[u8; 2]; 16] = [
+            [0x0F, 0x80],
+            [0x0F, 0x81],
+            [0x0F, 0x82],
+            [0x0F, 0x83],
+            [0x0F, 0x84],
+            [0x0F, 0x85],
+            [0x0F, 0x86],
+            [0x0F, 0x87],
+            [0x0F, 0x88],
+            [0x0F, 0x89],
+            [0x0F, 0x8A],
+            [0x0F, 0x8B],
+            [0x0F, 0x8C],
+            [0x0F, 0x8D],
+            [0x0F, 0x8E],
+            [0x0F, 0x8F],
+        ];
+        match self {
+            BranchKind::Jmp => (Some(0xEB), &[0xE9]),
+            BranchKind::Call => (None, &[0xE8]),
+            BranchKind::Jcc(condition_code) => (
+                Some(0x70 + condition_code),
+                &JCC_REL32[condition_code as usize],
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_names() {
+        assert_eq!(Gpr::from_register_name("rax"), Some(Gpr::Rax));
+        assert_eq!(Gpr::from_register_name("EBX"), Some(Gpr::Rbx));
+        assert_eq!(Gpr::from_register_name("ch"), Some(Gpr::Rcx));
+        assert_eq!(Gpr::from_register_name("sil"), Some(Gpr::Rsi));
+        assert_eq!(Gpr::from_register_name("r8"), Some(Gpr::R8));
+        assert_eq!(Gpr::from_register_name("r10d"), Some(Gpr::R10));
+        assert_eq!(Gpr::from_register_name("r15b"), Some(Gpr::R15));
+        assert_eq!(Gpr::from_register_name("r7"), None);
+        assert_eq!(Gpr::from_register_name("r16"), None);
+        assert_eq!(Gpr::from_register_name("rip"), None);
+        assert_eq!(Gpr::from_register_name("qword"), None);
+    }
+
+    #[test]
+    fn location_names() {
+        assert_eq!(
+            Location::from_name("ebx"),
+            Some(vec![Location::Register(Gpr::Rbx)])
+        );
+        assert_eq!(
+            Location::from_name("ZF"),
+            Some(vec![Location::Flag(Flag::Zf)])
+        );
+        assert_eq!(
+            Location::from_name("flags").map(|flags| flags.len()),
+            Some(6)
+        );
+        assert_eq!(Location::from_name("xmm0"), None);
+    }
+
+    #[test]
+    fn branch_aliases() {
+        assert_eq!(
+            BranchKind::from_mnemonic("jz"),
+            BranchKind::from_mnemonic("je")
+        );
+        assert_eq!(
+            BranchKind::from_mnemonic("JNAE"),
+            Some(BranchKind::Jcc(0x2))
+        );
+        assert_eq!(BranchKind::from_mnemonic("jmp"), Some(BranchKind::Jmp));
+        assert_eq!(BranchKind::from_mnemonic("mov"), None);
+        assert_eq!(
+            BranchKind::Jcc(0x5).opcodes(),
+            (Some(0x75), &[0x0F, 0x85][..])
+        );
+    }
+}
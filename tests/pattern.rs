@@ -7,8 +7,13 @@ use std::str::FromStr;
 use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult, Testable};
 use rand::prelude::*;
 
+use pattern_based_deobfuscator::disassembly::Disassembler;
+use pattern_based_deobfuscator::emulator::emulate;
 use pattern_based_deobfuscator::keystone_assemble;
 use pattern_based_deobfuscator::pattern::*;
+use pattern_based_deobfuscator::rewrite::clobbered_locations;
+use pattern_based_deobfuscator::semantics::State;
+use pattern_based_deobfuscator::x86::{Flag, Gpr};
 
 #[cfg(debug_assertions)]
 const QUICKCHECK_TESTS: u64 = 1_000;
@@ -66,6 +71,217 @@ fn quickcheck_test_multiple_instruction_pattern() {
     quickcheck(pattern_tests);
 }
 
+#[test]
+fn wildcard_skips_instructions_not_touching_registers() {
+    env_logger::try_init().ok();
+    let wildcard: PatternElement = serde_json::from_str(
+        r#"{ "wildcard": { "max": 2, "untouched": ["$reg:r1"], "name": "skipped" } }"#,
+    )
+    .unwrap();
+    let matcher = ObfuscationPatternMatcher::new(vec![
+        "push $reg:r1".parse().unwrap(),
+        wildcard,
+        "pop $reg:r1".parse().unwrap(),
+    ])
+    .unwrap();
+
+    let assemble = |asm: &str| keystone_assemble(asm.to_string(), 0).unwrap().bytes;
+
+    let matches = matcher.match_against(&assemble("push rbx\nmov rax, rcx\nadd rax, 8\npop rbx"));
+    assert_eq!(matches.len(), 1);
+    assert!(matches[0]
+        .variables()
+        .contains(&InstantiatedVariable::new_instructions(
+            "skipped".to_string(),
+            "mov rax, rcx\nadd rax, 8".to_string()
+        )));
+
+    // Touches the register
+    assert!(matcher
+        .match_against(&assemble("push rbx\nmov rbx, rcx\npop rbx"))
+        .is_empty());
+    // Too many instructions in between
+    assert!(matcher
+        .match_against(&assemble(
+            "push rbx\nmov rax, rcx\nmov rax, rdx\nmov rax, rsi\npop rbx"
+        ))
+        .is_empty());
+}
+
+#[test]
+fn repetition_and_alternatives() {
+    env_logger::try_init().ok();
+    let repeat: PatternElement =
+        serde_json::from_str(r#"{ "repeat": ["push $reg:r1", "pop $reg:r1"], "max": 3 }"#).unwrap();
+    let any_of: PatternElement =
+        serde_json::from_str(r#"{ "any_of": ["push $reg:r2", ["nop", "ret"]] }"#).unwrap();
+    let matcher =
+        ObfuscationPatternMatcher::new(vec!["int3".parse().unwrap(), repeat, any_of]).unwrap();
+
+    let assemble = |asm: &str| keystone_assemble(asm.to_string(), 0).unwrap().bytes;
+
+    let matches = matcher.match_against(&assemble(
+        "int3\npush rbx\npop rbx\npush rbx\npop rbx\npush rax",
+    ));
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].end(), 6);
+
+    // The repeated variable has to be consistent so only the first pair is part of the repetition
+    let matches = matcher.match_against(&assemble("int3\npush rbx\npop rbx\npush rcx\npop rcx"));
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].end(), 4);
+
+    let matches = matcher.match_against(&assemble("int3\npush rbx\npop rbx\nnop\nret"));
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].end(), 5);
+
+    // At least one repetition is required
+    assert!(matcher
+        .match_against(&assemble("int3\npush rax"))
+        .is_empty());
+
+    // A pattern may not start with an element which can be empty
+    let optional: PatternElement =
+        serde_json::from_str(r#"{ "repeat": ["nop"], "min": 0, "max": 2 }"#).unwrap();
+    assert_eq!(
+        ObfuscationPatternMatcher::new(vec![optional, "ret".parse().unwrap()]).err(),
+        Some(PatternError::EmptyFirstElement)
+    );
+
+    // Huge repetitions are rejected instead of exceeding the size limit of the regex
+    let huge: PatternElement =
+        serde_json::from_str(r#"{ "repeat": ["push $reg:r1", "pop $reg:r1"], "max": 100000 }"#)
+            .unwrap();
+    match ObfuscationPatternMatcher::new(vec![huge]) {
+        Err(PatternError::RegexFailed(_)) => {}
+        result => panic!("unexpected result: {:?}", result.map(|_| ())),
+    }
+}
+
+#[test]
+fn labeled_blocks_are_matched_at_branch_targets() {
+    env_logger::try_init().ok();
+    let pattern: ObfuscationPattern = serde_json::from_str(
+        r#"{
+            "pattern": ["int3", "jmp $label:push"],
+            "blocks": {
+                "push": ["push $reg:r1", "jmp $label:pop"],
+                "pop": ["pop $reg:r1"]
+            },
+            "replacement": []
+        }"#,
+    )
+    .unwrap();
+    let matcher = ObfuscationPatternMatcher::for_pattern(&pattern, FillerSet::default()).unwrap();
+
+    // 0x1000: int3
+    // 0x1001: jmp 0x1005
+    // 0x1003: pop rbx
+    // 0x1004: int3
+    // 0x1005: push rbx
+    // 0x1006: jmp 0x1003
+    let code = [0xCC, 0xEB, 0x02, 0x5B, 0xCC, 0x53, 0xEB, 0xFB];
+    let matches = matcher.match_against_at(&code, 0x1000);
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].range(), 0..3);
+    assert_eq!(
+        matches[0].blocks(),
+        &[("push".to_string(), 5..8), ("pop".to_string(), 3..4)][..]
+    );
+    assert!(matches[0]
+        .variables()
+        .contains(&InstantiatedVariable::new_label("pop".to_string(), 0x1003)));
+
+    // pop rdx instead of pop rbx
+    let code = [0xCC, 0xEB, 0x02, 0x5A, 0xCC, 0x53, 0xEB, 0xFB];
+    assert!(matcher.match_against_at(&code, 0x1000).is_empty());
+}
+
+#[test]
+fn excluded_registers_arent_matched() {
+    env_logger::try_init().ok();
+    let pattern: ObfuscationPattern = serde_json::from_str(
+        r#"{
+            "pattern": ["push $reg:r1", "pop $reg:r1"],
+            "replacement": [],
+            "excluded": ["$reg:r1 != rbx"]
+        }"#,
+    )
+    .unwrap();
+    let matcher = ObfuscationPatternMatcher::for_pattern(&pattern, FillerSet::default()).unwrap();
+
+    // push rbx; pop rbx
+    assert!(matcher.match_against(&[0x53, 0x5B]).is_empty());
+    // push rax; pop rax
+    assert_eq!(matcher.match_against(&[0x50, 0x58]).len(), 1);
+}
+
+#[test]
+fn matches_start_at_instruction_boundaries() {
+    env_logger::try_init().ok();
+    let matcher = ObfuscationPatternMatcher::new(vec![
+        InstructionPattern::from_str("push rbx").unwrap(),
+        InstructionPattern::from_str("pop rbx").unwrap(),
+    ])
+    .unwrap();
+
+    // 0x1000: mov eax, 0x5b53
+    // 0x1005: push rbx
+    // 0x1006: pop rbx
+    let code = [0xB8, 0x53, 0x5B, 0x00, 0x00, 0x53, 0x5B];
+    assert_eq!(matcher.match_against_at(&code, 0x1000).len(), 2);
+
+    let instruction_starts = [0x1000, 0x1005, 0x1006].iter().cloned().collect();
+    let constraints = MatchConstraints {
+        instruction_starts: Some(&instruction_starts),
+        ..MatchConstraints::default()
+    };
+    let result = matcher.match_against_constrained(&code, 0x1000, &constraints);
+    assert_eq!(result.matches.len(), 1);
+    assert_eq!(result.matches[0].range(), 5..7);
+    assert_eq!(result.discarded, 1);
+}
+
+#[test]
+fn quickcheck_test_replacements_behave_like_patterns() {
+    env_logger::try_init().ok();
+    let pattern_database = pattern_based_deobfuscator::load_pattern_database_from_json(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/pattern_database.json"
+    ))
+    .unwrap();
+    // The emulator only runs plain instruction sequences with number and register variables
+    let replacement_tests: Vec<_> = pattern_database
+        .patterns()
+        .iter()
+        .filter(|pattern| pattern.is_enabled() && pattern.instructions().is_some())
+        .filter(|pattern| {
+            pattern
+                .instructions()
+                .unwrap()
+                .into_iter()
+                .chain(pattern.replacement())
+                .flat_map(InstructionPattern::variables)
+                .all(|variable| match variable.typee() {
+                    VariableType::Number | VariableType::Register => true,
+                    _ => false,
+                })
+        })
+        .map(|pattern| ReplacementTest {
+            pattern: pattern.clone(),
+            excluded: pattern.exclusions().unwrap(),
+        })
+        .collect();
+    assert!(!replacement_tests.is_empty());
+
+    let mut qc = QuickCheck::new()
+        .tests(QUICKCHECK_TESTS)
+        .max_tests(QUICKCHECK_MAX_TESTS);
+    for test in replacement_tests {
+        qc.quickcheck(test);
+    }
+}
+
 struct PatternTest {
     matcher: ObfuscationPatternMatcher,
     blacklisted_widths: Vec<NumberWidth>,
@@ -134,14 +350,16 @@ impl Testable for PatternTest {
                             *register,
                         ));
                     }
-                    VariableType::Length => unimplemented!(),
+                    VariableType::Length | VariableType::Instructions | VariableType::Label => {
+                        unreachable!("the tested patterns only have number and register variables")
+                    }
                 }
             }
             vec
         };
         debug!("test instance: {}", instance);
 
-        if let Ok(assembled) = keystone_assemble(instance) {
+        if let Ok(assembled) = keystone_assemble(instance, 0) {
             let matches = self.matcher.match_against(&assembled.bytes);
             assert!(
                 matches.len() == 1,
@@ -149,10 +367,10 @@ impl Testable for PatternTest {
                 matches.len()
             );
 
-            let (ref found_variables, start, end) = matches[0];
+            let found_variables = matches[0].variables();
 
-            assert_eq!(start, 0);
-            assert_eq!(end, assembled.size as usize);
+            assert_eq!(matches[0].start(), 0);
+            assert_eq!(matches[0].end(), assembled.size as usize);
 
             debug!("expected variables: {:x?}", variable_instantiations);
             debug!("found variables: {:x?}", found_variables);
@@ -170,6 +388,143 @@ impl Testable for PatternTest {
     }
 }
 
+/// Runs an instance of the pattern and of its (padded) replacement from the same random state and
+/// compares the final states except for the clobbered locations
+struct ReplacementTest {
+    pattern: ObfuscationPattern,
+    /// Register instantiations the pattern doesn't match
+    excluded: Vec<(String, Register)>,
+}
+
+impl Testable for ReplacementTest {
+    fn result<G: Gen>(&self, gen: &mut G) -> TestResult {
+        const ADDRESS: u64 = 0x1000;
+
+        let pattern: Vec<InstructionPattern> = self
+            .pattern
+            .instructions()
+            .unwrap()
+            .into_iter()
+            .cloned()
+            .collect();
+        let mut pattern_instance = join_patterns(&pattern);
+        let mut replacement_instance = join_patterns(self.pattern.replacement());
+
+        let mut variables: Vec<InstantiatedVariable> = Vec::new();
+        for variable in pattern
+            .iter()
+            .chain(self.pattern.replacement())
+            .flat_map(|p| p.variables())
+        {
+            if variables.iter().any(|v| v.name() == variable.name()) {
+                continue;
+            }
+            let (placeholder, value, instantiated) = match variable.typee() {
+                VariableType::Number => {
+                    let number = Number::arbitrary(gen);
+                    (
+                        format!("$num:{}", variable.name()),
+                        number.to_hex(),
+                        InstantiatedVariable::new_number(
+                            variable.name().to_string(),
+                            number.value(),
+                        ),
+                    )
+                }
+                VariableType::Register => {
+                    let mut register = RegisterWrapper::arbitrary(gen);
+                    while self
+                        .excluded
+                        .contains(&(variable.name().to_string(), *register))
+                    {
+                        register = RegisterWrapper::arbitrary(gen);
+                    }
+                    (
+                        format!("$reg:{}", variable.name()),
+                        register.name().to_string(),
+                        InstantiatedVariable::new_register(variable.name().to_string(), *register),
+                    )
+                }
+                VariableType::Length | VariableType::Instructions | VariableType::Label => {
+                    unreachable!("the tested patterns only have number and register variables")
+                }
+            };
+            pattern_instance = pattern_instance.replace(&placeholder, &value);
+            replacement_instance = replacement_instance.replace(&placeholder, &value);
+            variables.push(instantiated);
+        }
+        debug!(
+            "test instance: {} -> {}",
+            pattern_instance, replacement_instance
+        );
+
+        let assemble = |instance: String| match keystone_assemble(instance.clone(), ADDRESS) {
+            Ok(assembled) => Some(assembled.bytes),
+            // Keystone doesn't accept an empty string
+            Err(_) if instance.is_empty() => Some(Vec::new()),
+            Err(_) => None,
+        };
+        let (pattern_code, mut replacement_code) =
+            match (assemble(pattern_instance), assemble(replacement_instance)) {
+                (Some(pattern_code), Some(replacement_code))
+                    if replacement_code.len() <= pattern_code.len() =>
+                {
+                    (pattern_code, replacement_code)
+                }
+                _ => return TestResult::discard(),
+            };
+        let padding = FillerSet::default().pad(pattern_code.len() - replacement_code.len());
+        replacement_code.extend(padding);
+
+        let mut registers: Vec<u64> = Gpr::all().iter().map(|_| gen.gen()).collect();
+        // Keep the stack aligned and away from the code
+        let rsp = Gpr::all().iter().position(|&gpr| gpr == Gpr::Rsp).unwrap();
+        registers[rsp] = 0x7FFF_0000_0000 + (gen.gen::<u32>() & !0xF) as u64;
+        let flags: Vec<bool> = Flag::all().iter().map(|_| gen.gen()).collect();
+
+        let disassembler = Disassembler::new();
+        let run = |code: &[u8]| {
+            emulate(
+                code,
+                ADDRESS,
+                State::concrete(&registers, &flags),
+                &disassembler,
+            )
+        };
+        // Only the pattern instance may be outside of the emulator's semantics model
+        let pattern_state = match run(&pattern_code) {
+            Ok(pattern_state) => pattern_state,
+            Err(_) => return TestResult::discard(),
+        };
+        let replacement_state = match run(&replacement_code) {
+            Ok(replacement_state) => replacement_state,
+            Err(error) => panic!(
+                "emulation of the replacement failed for {:x?}: {}",
+                variables, error
+            ),
+        };
+
+        let clobbered = clobbered_locations(&self.pattern, &variables).unwrap();
+        let differences = pattern_state.differences(&replacement_state, &clobbered);
+        assert!(
+            differences.is_empty(),
+            "replacement differs in {} for {:x?} from {:x?}",
+            differences.join(", "),
+            variables,
+            registers
+        );
+        TestResult::passed()
+    }
+}
+
+fn join_patterns(patterns: &[InstructionPattern]) -> String {
+    patterns
+        .iter()
+        .map(|p| p.pattern().to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone)]
 enum Number {